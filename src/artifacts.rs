@@ -1,8 +1,25 @@
-use crate::{redux_dir, FileStamp};
-use anyhow::Context;
+use crate::{
+    config,
+    filestamp::{hash_file, read_symlink, FileKind},
+    redux_dir, remove_path, replace_path,
+    tree::{self, Tree, Visit},
+    DepGraph, FileStamp,
+};
+use anyhow::{anyhow, ensure, Context};
 use blake3::Hash;
-use std::{collections::HashSet, path::PathBuf, sync::LazyLock};
-use tracing::debug;
+use rustix::fs::ioctl_ficlone;
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fs::{File, Permissions},
+    io::Read,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+    time::SystemTime,
+};
+use tracing::{debug, info, trace};
+use uuid::Uuid;
 
 pub static ARTIFACTS_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
     let path = redux_dir().join("artifacts");
@@ -10,47 +27,497 @@ pub static ARTIFACTS_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
     path
 });
 
-/// A cache of the contents of redux_dir/artifacts
-pub struct Artifacts(HashSet<Hash>);
+/// Blobs which this process has put in the store (or found already there)
+static INSERTED: LazyLock<Mutex<HashSet<Hash>>> = LazyLock::new(Default::default);
+
+/// How a blob is stored on disk.  A blob is always named after the hash of its
+/// _uncompressed_ contents; the encoding is indicated by the extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Plain,
+    Zstd,
+}
+
+impl Encoding {
+    const ALL: [Encoding; 2] = [Encoding::Plain, Encoding::Zstd];
+
+    fn extension(self) -> &'static str {
+        match self {
+            Encoding::Plain => "",
+            Encoding::Zstd => ".zst",
+        }
+    }
+}
+
+/// Parse the name of a blob (minus the shard prefix).  `None` means it isn't a
+/// blob (eg. it's a temporary file).
+fn parse_blob_name(shard: &str, name: &str) -> Option<(Hash, Encoding)> {
+    let (name, encoding) = match name.strip_suffix(Encoding::Zstd.extension()) {
+        Some(x) => (x, Encoding::Zstd),
+        None => (name, Encoding::Plain),
+    };
+    Some((format!("{shard}{name}").parse().ok()?, encoding))
+}
+
+/// Blobs are sharded by the first byte of their hash, ie. the blob with hash
+/// `abcdef...` lives at `artifacts/ab/cdef...`.
+fn blob_path(hash: Hash, encoding: Encoding) -> PathBuf {
+    let hex = hash.to_hex();
+    let (shard, rest) = hex.split_at(2);
+    ARTIFACTS_DIR
+        .join(shard)
+        .join(format!("{rest}{}", encoding.extension()))
+}
+
+/// A blob in the artifact store
+pub struct Blob {
+    pub path: PathBuf,
+    pub hash: Hash,
+    pub encoding: Encoding,
+}
+
+/// A handle on the contents of redux_dir/artifacts
+///
+/// The mtime of each blob records when it was last inserted or restored.  If
+/// `redux.maxArtifactsSize` is set, the least-recently-used blobs are evicted
+/// to keep the store within budget.
+pub struct Artifacts(());
 
 impl Artifacts {
     pub fn new() -> anyhow::Result<Artifacts> {
         std::fs::create_dir_all(&*ARTIFACTS_DIR)?;
-        let mut xs = HashSet::default();
+        Ok(Artifacts(()))
+    }
+
+    /// Where a blob is stored, and how it's encoded
+    pub fn find(&self, hash: Hash) -> Option<(PathBuf, Encoding)> {
+        Encoding::ALL
+            .into_iter()
+            .map(|x| (blob_path(hash, x), x))
+            .find(|(path, _)| path.exists())
+    }
+
+    pub fn store_path(&self, hash: Hash) -> Option<PathBuf> {
+        self.find(hash).map(|x| x.0)
+    }
+
+    pub fn contains(&self, hash: Hash) -> bool {
+        self.find(hash).is_some()
+    }
+
+    /// List every blob in the store
+    pub fn blobs(&self) -> anyhow::Result<Vec<Blob>> {
+        let mut blobs = vec![];
         for ent in std::fs::read_dir(&*ARTIFACTS_DIR)? {
-            let path = ent?.path();
-            let fname = path.file_name().unwrap();
-            let fname = fname.to_str().unwrap();
-            xs.insert(fname.parse().unwrap());
+            let ent = ent?;
+            let shard = ent.file_name();
+            let Some(shard) = shard.to_str().filter(|x| x.len() == 2) else {
+                continue;
+            };
+            if !ent.file_type()?.is_dir() {
+                continue;
+            }
+            for ent in std::fs::read_dir(ent.path())? {
+                let path = ent?.path();
+                let name = path.file_name().unwrap().to_str();
+                let Some((hash, encoding)) = name.and_then(|x| parse_blob_name(shard, x)) else {
+                    continue;
+                };
+                blobs.push(Blob {
+                    path,
+                    hash,
+                    encoding,
+                });
+            }
         }
-        Ok(Artifacts(xs))
+        Ok(blobs)
     }
 
-    pub fn store_path(hash: Hash) -> PathBuf {
-        ARTIFACTS_DIR.join(hash.to_string())
+    /// Open a blob for reading, decompressing it if necessary
+    pub fn open_blob(&self, hash: Hash) -> anyhow::Result<Box<dyn Read>> {
+        let (path, encoding) = self
+            .find(hash)
+            .ok_or_else(|| anyhow!("{hash}: Missing from the store"))?;
+        let file = File::open(&path).with_context(|| format!("Opening {}", path.display()))?;
+        match encoding {
+            Encoding::Plain => Ok(Box::new(file)),
+            Encoding::Zstd => Ok(Box::new(zstd::Decoder::new(file)?)),
+        }
+    }
+
+    /// Read a blob into memory, decompressing it if necessary
+    pub fn read_blob(&self, hash: Hash) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = vec![];
+        self.open_blob(hash)?.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn read_tree(&self, hash: Hash) -> anyhow::Result<Tree> {
+        let bytes = self.read_blob(hash)?;
+        std::str::from_utf8(&bytes)?
+            .parse()
+            .with_context(|| format!("{hash}: Corrupt tree object"))
+    }
+
+    /// The blobs needed to restore the given file: just the file itself, or
+    /// (for a dir) its tree object plus everything it references.
+    pub fn closure(&self, kind: FileKind, hash: Hash) -> anyhow::Result<Vec<Hash>> {
+        let mut hashes = vec![hash];
+        if kind == FileKind::Dir {
+            for x in self.read_tree(hash)?.0 {
+                hashes.extend(self.closure(x.kind, x.hash)?);
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Whether everything needed to restore `file` is in the store
+    pub fn contains_stamp(&self, file: &FileStamp) -> bool {
+        self.closure(file.kind, file.hash)
+            .is_ok_and(|xs| xs.into_iter().all(|x| self.contains(x)))
     }
 
     pub fn insert(&mut self, file: &FileStamp) -> anyhow::Result<()> {
-        if self.0.contains(&file.hash) {
-            debug!("{}: contents already in the store", file.path);
-        } else {
-            let to = Self::store_path(file.hash);
-            std::fs::copy(file.path.to_abs(), to)?;
+        let added = match file.kind {
+            FileKind::File | FileKind::Exec => self.insert_file(&file.abs_path(), file.hash)?,
+            FileKind::Symlink => {
+                let target = read_symlink(&file.abs_path())?;
+                ensure!(
+                    blake3::hash(&target) == file.hash,
+                    "{}: Changed while being stored",
+                    file.path
+                );
+                self.insert_bytes(&target, file.hash)?
+            }
+            FileKind::Dir => {
+                let mut added = false;
+                let hash = tree::hash_dir(&file.abs_path(), &mut |x| {
+                    added |= match x {
+                        Visit::File(path, hash) => self.insert_file(path, hash)?,
+                        Visit::Bytes(bytes, hash) => self.insert_bytes(bytes, hash)?,
+                    };
+                    Ok(())
+                })?;
+                ensure!(
+                    hash == file.hash,
+                    "{}: Changed while being stored",
+                    file.path
+                );
+                added
+            }
+        };
+        if added {
             debug!("{}: contents added to the store", file.path);
-            self.0.insert(file.hash);
+        } else {
+            debug!("{}: contents already in the store", file.path);
         }
         Ok(())
     }
 
+    /// Add a blob from an untrusted source (eg. a remote cache).  It's checked
+    /// against the expected hash before it goes into the store.
+    pub fn insert_from(&mut self, hash: Hash, mut reader: impl Read) -> anyhow::Result<()> {
+        let tmp = ARTIFACTS_DIR.join(format!(".{}.download", Uuid::new_v4()));
+        let mut go = || -> anyhow::Result<()> {
+            std::io::copy(&mut reader, &mut File::create_new(&tmp)?)?;
+            let actual = hash_file(&tmp)?;
+            ensure!(actual == hash, "Expected hash {hash}, but got {actual}");
+            self.insert_file(&tmp, hash)?;
+            Ok(())
+        };
+        let res = go();
+        let _ = std::fs::remove_file(&tmp); // Might be missing
+        res.with_context(|| format!("{hash}: Adding to the store"))?;
+        Ok(())
+    }
+
+    /// Returns false if the blob was already in the store
+    fn insert_file(&self, src: &Path, hash: Hash) -> anyhow::Result<bool> {
+        INSERTED.lock().unwrap().insert(hash);
+        // If touching fails, someone else must have evicted it just now
+        if self.store_path(hash).is_some_and(|x| touch(&x).is_ok()) {
+            return Ok(false);
+        }
+        let encoding = write_blob(src, hash)?;
+        trace!("{}: Stored as {hash} ({encoding:?})", src.display());
+        Ok(true)
+    }
+
+    /// Returns false if the blob was already in the store
+    fn insert_bytes(&self, bytes: &[u8], hash: Hash) -> anyhow::Result<bool> {
+        INSERTED.lock().unwrap().insert(hash);
+        if self.store_path(hash).is_some_and(|x| touch(&x).is_ok()) {
+            return Ok(false);
+        }
+        let tmp = ARTIFACTS_DIR.join(format!(".{}.tmp", Uuid::new_v4()));
+        std::fs::write(&tmp, bytes)?;
+        move_into_store(&tmp, hash, Encoding::Plain)?;
+        Ok(true)
+    }
+
     pub fn restore(&self, file: &FileStamp) -> anyhow::Result<()> {
-        assert!(self.0.contains(&file.hash));
-        let from = Self::store_path(file.hash);
-        std::fs::copy(from, file.path.to_abs()).context("Copy artifact")?;
+        self.restore_to(file, &file.path.to_abs())
+    }
+
+    /// Like `restore()`, but writes the contents to `to` instead of the
+    /// file's own path
+    pub fn restore_to(&self, file: &FileStamp, to: &Path) -> anyhow::Result<()> {
+        // Restore to a temporary path and then move it into place, so the
+        // target is replaced atomically
+        let tmp = to.with_file_name(format!(
+            ".redux_{}.{}.restore",
+            file.path.file_name(),
+            Uuid::new_v4(),
+        ));
+        let res = self.restore_entry(file.kind, file.hash, &tmp);
+        let transfer = match res {
+            Ok(x) => x,
+            Err(e) => {
+                let _ = remove_path(&tmp); // Might be missing
+                return Err(e.context(format!("{}: Restoring", file.path)));
+            }
+        };
+        replace_path(&tmp, to).context("Move restored file into place")?;
         debug!(
-            "{}: Restored contents @{}",
+            "{}: Restored contents @{} ({transfer:?})",
             file.path,
             &file.hash.to_hex()[..8],
         );
         Ok(())
     }
+
+    fn restore_entry(&self, kind: FileKind, hash: Hash, to: &Path) -> anyhow::Result<Transfer> {
+        match kind {
+            FileKind::File | FileKind::Exec => self.restore_file(kind, hash, to),
+            FileKind::Symlink => {
+                let target = self.read_blob(hash)?;
+                std::os::unix::fs::symlink(OsStr::from_bytes(&target), to)
+                    .with_context(|| format!("Creating symlink {}", to.display()))?;
+                Ok(Transfer::Symlink)
+            }
+            FileKind::Dir => self.restore_dir(hash, to),
+        }
+    }
+
+    fn restore_file(&self, kind: FileKind, hash: Hash, to: &Path) -> anyhow::Result<Transfer> {
+        let (from, encoding) = self
+            .find(hash)
+            .ok_or_else(|| anyhow!("{hash}: Missing from the store"))?;
+        // A blob may be shared by executable and non-executable files, so we
+        // can't hardlink executables: they need a different mode to the blob
+        let hardlink = config::hardlinks() && kind == FileKind::File;
+        let transfer = match encoding {
+            Encoding::Plain => clone_file(&from, to, hardlink)?,
+            Encoding::Zstd => {
+                let input = File::open(&from).context("Open artifact")?;
+                let output = File::create_new(to).context("Create output")?;
+                zstd::stream::copy_decode(input, output).context("Decompress artifact")?;
+                Transfer::Decompress
+            }
+        };
+        // Blobs have whatever mode the file had when it was first stored, so
+        // set the mode explicitly.  (Hardlinks are read-only, and have to stay
+        // that way.)
+        if transfer != Transfer::Hardlink {
+            std::fs::set_permissions(to, Permissions::from_mode(kind.mode()))?;
+        }
+        touch(&from)?;
+        Ok(transfer)
+    }
+
+    fn restore_dir(&self, hash: Hash, to: &Path) -> anyhow::Result<Transfer> {
+        let tree = self.read_tree(hash)?;
+        std::fs::create_dir(to).with_context(|| format!("Creating dir {}", to.display()))?;
+        for x in tree.0 {
+            self.restore_entry(x.kind, x.hash, &to.join(&x.name))?;
+        }
+        if let Some(path) = self.store_path(hash) {
+            touch(&path)?;
+        }
+        Ok(Transfer::Tree)
+    }
+
+    /// Evict least-recently-used blobs if the store is bigger than
+    /// `redux.maxArtifactsSize`.  This is done once per build (by the top-level
+    /// redux) rather than on every insert, since it has to load every trace.
+    ///
+    /// Returns the number of bytes freed.
+    pub fn enforce_configured_budget(&mut self) -> anyhow::Result<u64> {
+        match config::max_artifacts_size() {
+            Some(budget) => self.enforce_budget(budget),
+            None => Ok(0),
+        }
+    }
+
+    /// Evict least-recently-used blobs until the store occupies at most
+    /// `budget` bytes.  The outputs of currently-valid traces are never
+    /// evicted, and neither is anything this process has inserted (it may
+    /// belong to a trace which hasn't been committed yet), so the store may
+    /// remain over budget.
+    ///
+    /// Returns the number of bytes freed.
+    pub fn enforce_budget(&mut self, budget: u64) -> anyhow::Result<u64> {
+        let mut blobs = vec![];
+        let mut total = 0;
+        for blob in self.blobs()? {
+            let meta = std::fs::metadata(&blob.path)?;
+            total += meta.len();
+            blobs.push((meta.modified()?, meta.len(), blob));
+        }
+        if total <= budget {
+            return Ok(0);
+        }
+        info!("Artifact store is over budget ({total} > {budget} bytes); evicting");
+
+        let mut protected = INSERTED.lock().unwrap().clone();
+        for x in DepGraph::load()?.live_outputs() {
+            protected.extend(self.closure(x.kind, x.hash).unwrap_or_default());
+        }
+        // Oldest first
+        blobs.sort_by_key(|(atime, _, _)| *atime);
+        let mut freed = 0;
+        for (_, len, blob) in blobs {
+            if total - freed <= budget {
+                break;
+            }
+            if protected.contains(&blob.hash) {
+                continue;
+            }
+            match std::fs::remove_file(&blob.path) {
+                Ok(()) => (),
+                // Another process is evicting concurrently
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Removing {}", blob.path.display()))
+                }
+            }
+            debug!("{}: Evicted from the store", &blob.hash.to_hex()[..8]);
+            freed += len;
+        }
+        Ok(freed)
+    }
+}
+
+/// Record that a blob has just been used
+fn touch(path: &Path) -> anyhow::Result<()> {
+    File::open(path)
+        .and_then(|f| f.set_modified(SystemTime::now()))
+        .with_context(|| format!("Touching {}", path.display()))
+}
+
+/// Copy a file into the store, compressing it if `redux.compression` says so.
+/// The blob is written to a temporary file first, so that other processes
+/// never see a partially-written blob.
+fn write_blob(src: &Path, hash: Hash) -> anyhow::Result<Encoding> {
+    let tmp = ARTIFACTS_DIR.join(format!(".{}.tmp", Uuid::new_v4()));
+    let go = || -> anyhow::Result<Encoding> {
+        if let Some(level) = config::compression_level() {
+            zstd::stream::copy_encode(File::open(src)?, File::create_new(&tmp)?, level)?;
+            if std::fs::metadata(&tmp)?.len() < std::fs::metadata(src)?.len() {
+                return Ok(Encoding::Zstd);
+            }
+            debug!("{}: Doesn't compress; storing as-is", src.display());
+            std::fs::remove_file(&tmp)?;
+        }
+        // Never hardlink on the way in: `src` may be a source which the user
+        // is about to edit
+        let transfer = clone_file(src, &tmp, false)?;
+        debug!("{}: Stored ({transfer:?})", src.display());
+        Ok(Encoding::Plain)
+    };
+    match go() {
+        Ok(encoding) => {
+            move_into_store(&tmp, hash, encoding)?;
+            Ok(encoding)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp); // Might be missing
+            Err(e.context(format!("{}: Adding to the store", src.display())))
+        }
+    }
+}
+
+fn move_into_store(tmp: &Path, hash: Hash, encoding: Encoding) -> anyhow::Result<()> {
+    let to = blob_path(hash, encoding);
+    std::fs::create_dir_all(to.parent().unwrap())?;
+    std::fs::rename(tmp, to)?;
+    Ok(())
+}
+
+/// How a file was moved into or out of the store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transfer {
+    Reflink,
+    Hardlink,
+    Copy,
+    Decompress,
+    Symlink,
+    /// A directory, restored entry-by-entry
+    Tree,
+}
+
+/// Create `to` with the same contents as `from`, as cheaply as possible:
+///
+/// 1. a reflink (on filesystems which support it, eg. btrfs and xfs);
+/// 2. a hardlink, if `hardlink` is set.  Both files are made read-only, so
+///    that in-place edits can't corrupt the store;
+/// 3. a plain old copy.
+fn clone_file(from: &Path, to: &Path, hardlink: bool) -> anyhow::Result<Transfer> {
+    match reflink(from, to) {
+        Ok(()) => return Ok(Transfer::Reflink),
+        Err(e) => trace!("{}: Can't reflink: {e}", to.display()),
+    }
+    if hardlink {
+        match std::fs::hard_link(from, to) {
+            Ok(()) => {
+                let mut perms = std::fs::metadata(to)?.permissions();
+                perms.set_readonly(true);
+                std::fs::set_permissions(to, perms)?;
+                return Ok(Transfer::Hardlink);
+            }
+            Err(e) => trace!("{}: Can't hardlink: {e}", to.display()),
+        }
+    }
+    std::fs::copy(from, to).with_context(|| format!("Copying to {}", to.display()))?;
+    Ok(Transfer::Copy)
+}
+
+fn reflink(from: &Path, to: &Path) -> std::io::Result<()> {
+    let src = File::open(from)?;
+    let dst = File::create_new(to)?;
+    let res = ioctl_ficlone(&dst, &src)
+        .map_err(std::io::Error::from)
+        .and_then(|()| dst.set_permissions(src.metadata()?.permissions()));
+    if res.is_err() {
+        let _ = std::fs::remove_file(to);
+    }
+    res
+}
+
+/// Older versions of redux kept all blobs directly in the artifacts dir.  Move
+/// any such blobs into their shards.
+pub fn migrate_flat_layout(dir: &Path) -> anyhow::Result<()> {
+    for ent in std::fs::read_dir(dir)? {
+        let ent = ent?;
+        if !ent.file_type()?.is_file() {
+            continue;
+        }
+        let name = ent.file_name();
+        let Some(name) = name.to_str().filter(|x| x.len() > 2) else {
+            continue;
+        };
+        let (shard, rest) = name.split_at(2);
+        if parse_blob_name(shard, rest).is_none() {
+            continue;
+        }
+        let shard_dir = dir.join(shard);
+        std::fs::create_dir_all(&shard_dir)?;
+        match std::fs::rename(ent.path(), shard_dir.join(rest)) {
+            Ok(()) => debug!("{name}: Moved into shard"),
+            // Another process is migrating concurrently
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e).with_context(|| format!("Migrating {name}")),
+        }
+    }
+    Ok(())
 }
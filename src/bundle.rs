@@ -0,0 +1,139 @@
+//! Cache bundles: a single file holding the traces and artifacts needed to
+//! restore some targets, for shipping a cache around without setting up a
+//! remote (eg. as a CI artifact).
+//!
+//! A bundle is a zstd-compressed tarball containing `artifacts/<hash>` (the
+//! uncompressed contents of each blob) followed by `traces/<hash>.trace`.  The
+//! artifacts come first so that, if an import is interrupted, we never end up
+//! with a trace whose outputs are missing.
+
+use crate::{
+    artifacts::Encoding, depgraph::BuildTree, index, job_for_target, Artifacts, DepGraph, LocalPath,
+};
+use anyhow::{anyhow, Context};
+use blake3::Hash;
+use std::{collections::HashSet, fs::File, io::Read, path::Path};
+use tracing::{debug, warn};
+
+/// What was exported or imported.  For imports, only things which weren't
+/// already in the local cache are counted.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BundleStats {
+    pub traces: usize,
+    pub artifacts: usize,
+}
+
+/// Write a bundle containing everything needed to restore `targets`.  If
+/// `targets` is empty, the bundle contains every trace which is valid in the
+/// current worktree.
+pub fn export_cache(path: &Path, targets: &[LocalPath]) -> anyhow::Result<BundleStats> {
+    let mut trees = vec![];
+    if targets.is_empty() {
+        let graph = DepGraph::load()?;
+        trees.extend(
+            graph
+                .traces
+                .keys()
+                .filter_map(|job| graph.valid_trace_for(job)),
+        );
+    } else {
+        for target in targets {
+            let job = job_for_target(target)?;
+            let tree = DepGraph::load_for(&job)?
+                .valid_trace_for(&job)
+                .ok_or_else(|| anyhow!("{target}: Nothing to export (try building it first)"))?;
+            trees.push(tree);
+        }
+    }
+
+    // Intermediates' traces are needed to validate the trees, but only the
+    // outputs of the trees themselves need to be restorable
+    let artifacts = Artifacts::new()?;
+    let mut traces = vec![];
+    let mut blobs = vec![];
+    let mut seen_traces = HashSet::<Hash>::default();
+    let mut seen_blobs = HashSet::<Hash>::default();
+    for tree in &trees {
+        for x in &tree.outputs {
+            match artifacts.closure(x.kind, x.hash) {
+                Ok(hashes) => blobs.extend(hashes.into_iter().filter(|x| seen_blobs.insert(*x))),
+                Err(e) => warn!("{}: Not exporting: {e:#}", x.path),
+            }
+        }
+        fn go(tree: &BuildTree, traces: &mut Vec<Hash>, seen: &mut HashSet<Hash>) {
+            if seen.insert(tree.trace) {
+                traces.push(tree.trace);
+            }
+            for (_, tree) in &tree.intermediates {
+                go(tree, traces, seen);
+            }
+        }
+        go(tree, &mut traces, &mut seen_traces);
+    }
+
+    let file = File::create(path).with_context(|| format!("Creating {}", path.display()))?;
+    let mut tar = tar::Builder::new(zstd::Encoder::new(file, 3)?.auto_finish());
+    let mut stats = BundleStats::default();
+    for hash in blobs {
+        let name = format!("artifacts/{hash}");
+        match artifacts.find(hash) {
+            Some((path, Encoding::Plain)) => tar.append_path_with_name(path, &name)?,
+            Some((_, Encoding::Zstd)) => {
+                let bytes = artifacts.read_blob(hash)?;
+                let mut header = tar::Header::new_gnu();
+                header.set_size(bytes.len() as u64);
+                header.set_mode(0o644);
+                tar.append_data(&mut header, &name, bytes.as_slice())?;
+            }
+            None => {
+                warn!("{hash}: Missing from the store; not exporting");
+                continue;
+            }
+        }
+        stats.artifacts += 1;
+    }
+    for hash in traces {
+        tar.append_path_with_name(index::trace_path(hash), format!("traces/{hash}.trace"))?;
+        stats.traces += 1;
+    }
+    tar.into_inner()?;
+    Ok(stats)
+}
+
+/// Merge a bundle into the local cache.  Everything is checked against its
+/// hash on the way in.
+pub fn import_cache(path: &Path) -> anyhow::Result<BundleStats> {
+    let file = File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+    let mut tar = tar::Archive::new(zstd::Decoder::new(file)?);
+    let mut artifacts = Artifacts::new()?;
+    let mut stats = BundleStats::default();
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        if let Some(hash) = name
+            .strip_prefix("traces/")
+            .and_then(|x| x.strip_suffix(".trace"))
+        {
+            let hash: Hash = hash.parse().with_context(|| format!("{name}: Bad name"))?;
+            if index::trace_path(hash).exists() {
+                continue;
+            }
+            let mut txt = vec![];
+            entry.read_to_end(&mut txt)?;
+            index::import_trace(hash, &txt).with_context(|| format!("Importing {name}"))?;
+            debug!("{name}: Imported");
+            stats.traces += 1;
+        } else if let Some(hash) = name.strip_prefix("artifacts/") {
+            let hash: Hash = hash.parse().with_context(|| format!("{name}: Bad name"))?;
+            if artifacts.contains(hash) {
+                continue;
+            }
+            artifacts.insert_from(hash, entry)?;
+            debug!("{name}: Imported");
+            stats.artifacts += 1;
+        } else {
+            warn!("{name}: Unexpected entry in bundle; skipping");
+        }
+    }
+    Ok(stats)
+}
@@ -0,0 +1,160 @@
+//! Settings read from git config.  All keys live in the `redux` section, so
+//! they can be set per-repo (`git config redux.foo bar`), per-user, or via
+//! `GIT_CONFIG_COUNT`/`GIT_CONFIG_KEY_<n>`/`GIT_CONFIG_VALUE_<n>` in CI.
+
+use crate::{local_path::project_base, LocalPath, REPO};
+use globset::GlobBuilder;
+use std::path::PathBuf;
+use tracing::warn;
+
+fn string(key: &str) -> Option<String> {
+    let repo = REPO.to_thread_local();
+    let config = repo.config_snapshot();
+    config.string(key).map(|x| x.to_string())
+}
+
+fn strings(key: &str) -> Vec<String> {
+    let repo = REPO.to_thread_local();
+    let config = repo.config_snapshot();
+    config
+        .strings(key)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|x| x.to_string())
+        .collect()
+}
+
+fn boolean(key: &str) -> Option<bool> {
+    let repo = REPO.to_thread_local();
+    let config = repo.config_snapshot();
+    match config.try_boolean(key)? {
+        Ok(x) => Some(x),
+        Err(e) => {
+            warn!("{key}: Ignoring invalid value: {e}");
+            None
+        }
+    }
+}
+
+fn integer(key: &str) -> Option<i64> {
+    let repo = REPO.to_thread_local();
+    let config = repo.config_snapshot();
+    match config.try_integer(key)? {
+        Ok(x) => Some(x),
+        Err(e) => {
+            warn!("{key}: Ignoring invalid value: {e}");
+            None
+        }
+    }
+}
+
+/// `redux.maxArtifactsSize`: the number of bytes the artifact store may occupy
+/// before the least-recently-used blobs are evicted.  Accepts the usual `k`,
+/// `m`, and `g` suffixes.  Unlimited if unset.
+pub fn max_artifacts_size() -> Option<u64> {
+    let x = integer("redux.maxArtifactsSize")?;
+    match u64::try_from(x) {
+        Ok(x) => Some(x),
+        Err(_) => {
+            warn!("redux.maxArtifactsSize: Ignoring negative value");
+            None
+        }
+    }
+}
+
+/// `redux.compression`: how to compress new artifacts: `none` (the default) or
+/// `zstd`.  `redux.compressionLevel` sets the zstd level (defaults to 3).
+///
+/// Returns the zstd level to use, or `None` if artifacts shouldn't be
+/// compressed.
+pub fn compression_level() -> Option<i32> {
+    match string("redux.compression")?.as_str() {
+        "none" => None,
+        "zstd" => {
+            let level = integer("redux.compressionLevel").unwrap_or(3);
+            Some(level.try_into().unwrap_or(3))
+        }
+        x => {
+            warn!("redux.compression: Ignoring unknown value {x:?}");
+            None
+        }
+    }
+}
+
+/// `redux.remote`: the base URL of an HTTP cache to consult when nothing in
+/// the local store can be re-used.  See `remote` for the protocol.
+pub fn remote() -> Option<String> {
+    string("redux.remote").filter(|x| !x.is_empty())
+}
+
+/// `redux.remoteUpload`: upload the trace and outputs of every job which redux
+/// runs to `redux.remote`.  Defaults to false.  (Shared caches are always
+/// written to.)
+pub fn remote_upload() -> bool {
+    boolean("redux.remoteUpload").unwrap_or(false)
+}
+
+/// `redux.sharedCache`: directories (eg. on a shared filesystem) to use as
+/// additional caches.  May be given more than once.  Relative paths are
+/// relative to the top of the worktree.
+pub fn shared_caches() -> Vec<PathBuf> {
+    strings("redux.sharedCache")
+        .into_iter()
+        .filter(|x| !x.is_empty())
+        .map(|x| project_base().join(x))
+        .collect()
+}
+
+/// `redux.hardlinks`: if reflinks aren't available, hardlink files out of the
+/// artifact store instead of copying them.  Hardlinked files are made
+/// read-only.  Defaults to false.
+pub fn hardlinks() -> bool {
+    boolean("redux.hardlinks").unwrap_or(false)
+}
+
+/// `redux.signingKey`: a file containing the key to sign new traces with (as
+/// created by `redux --gen-signing-key`).  Relative paths are relative to the
+/// top of the worktree.
+pub fn signing_key() -> Option<PathBuf> {
+    string("redux.signingKey")
+        .filter(|x| !x.is_empty())
+        .map(|x| project_base().join(x))
+}
+
+/// `redux.trustedKey`: the hex-encoded public keys whose signatures we accept.
+/// May be given more than once.  If set, unsigned traces are ignored.
+pub fn trusted_keys() -> Vec<String> {
+    strings("redux.trustedKey")
+        .into_iter()
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+/// `redux.gitIndex`: record the git blob ID of checked-in sources, and trust
+/// git's index to say whether they've changed.  Defaults to false.
+pub fn git_index() -> bool {
+    boolean("redux.gitIndex").unwrap_or(false)
+}
+
+/// `redux.captureStdout`: for compatibility with apenwarr's redo, if a dofile
+/// doesn't write to `$3` then use whatever it wrote to stdout as its output.
+/// Defaults to false.  `redux.captureStdoutFor` turns this on for just the
+/// dofiles matching the given patterns (relative to the top of the worktree,
+/// and with the same syntax as `--glob`), and may be given more than once.
+pub fn capture_stdout(dofile: &LocalPath) -> bool {
+    if boolean("redux.captureStdout").unwrap_or(false) {
+        return true;
+    }
+    strings("redux.captureStdoutFor")
+        .into_iter()
+        .filter_map(
+            |x| match GlobBuilder::new(&x).literal_separator(true).build() {
+                Ok(x) => Some(x.compile_matcher()),
+                Err(e) => {
+                    warn!("redux.captureStdoutFor: Ignoring {x:?}: {e}");
+                    None
+                }
+            },
+        )
+        .any(|x| x.is_match(dofile.as_path()))
+}
@@ -1,16 +1,20 @@
 use crate::{
+    config, index,
+    interpreter::Interpreter,
     redux_dir,
+    signing::TrustList,
     trace::{JobSpec, Trace, TraceFile},
-    FileStamp, RuleSet,
+    FileStamp, GlobStamp, LocalPath, RuleSet,
 };
+use blake3::Hash;
 use std::{
-    collections::{BTreeMap, BTreeSet, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt,
     path::PathBuf,
     sync::LazyLock,
     time::SystemTime,
 };
-use tracing::debug;
+use tracing::{debug, warn};
 use yansi::Paint;
 
 pub static TRACES_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
@@ -19,11 +23,54 @@ pub static TRACES_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
     path
 });
 
+/// Where a trace's dependencies are checked.  Normally that's the current
+/// worktree, but `--rev` checks them against a commit's tree instead.
+pub trait Checkout {
+    fn is_source_valid(&self, trace: &Trace, source: &FileStamp) -> bool;
+    fn is_glob_valid(&self, glob: &GlobStamp, own_outputs: &[LocalPath]) -> bool;
+    /// Broken symlinks count as existing
+    fn exists(&self, path: &LocalPath) -> bool;
+    /// As recorded in the trace (see `Interpreter`)
+    fn interpreter(&self, dofile: &LocalPath) -> Option<String>;
+    /// Whether `job`'s rule is still the one which would be used to build its
+    /// target
+    fn is_job_current(&self, job: &JobSpec) -> bool;
+}
+
+/// The current worktree
+pub struct CurrentWorktree;
+
+impl Checkout for CurrentWorktree {
+    fn is_source_valid(&self, trace: &Trace, source: &FileStamp) -> bool {
+        trace.is_source_valid(source)
+    }
+
+    fn is_glob_valid(&self, glob: &GlobStamp, own_outputs: &[LocalPath]) -> bool {
+        glob.is_valid(own_outputs).unwrap_or(false)
+    }
+
+    fn exists(&self, path: &LocalPath) -> bool {
+        path.to_abs().symlink_metadata().is_ok()
+    }
+
+    fn interpreter(&self, dofile: &LocalPath) -> Option<String> {
+        Interpreter::for_dofile(&dofile.to_abs())
+            .ok()
+            .map(|x| x.to_string())
+    }
+
+    fn is_job_current(&self, job: &JobSpec) -> bool {
+        RuleSet::is_job_current(job)
+    }
+}
+
 // TODO: It's really a DAG
 // TODO: BTreeMap<JobSpec, Trace>?
 #[derive(Clone)]
 pub struct BuildTree {
     pub job: JobSpec,
+    /// The hash of the trace which this tree was built from
+    pub trace: Hash,
     pub sources: Vec<FileStamp>,
     pub intermediates: Vec<(FileStamp, BuildTree)>,
     pub outputs: Vec<FileStamp>,
@@ -33,8 +80,11 @@ pub struct BuildTree {
 impl fmt::Display for BuildTree {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut printed_jobs = BTreeSet::<JobSpec>::default();
-        fn to_tt(tree: &BuildTree, printed_jobs: &mut BTreeSet<JobSpec>) -> termtree::Tree<String> {
-            let relevant_output = &tree.outputs[0]; // FIXME
+        fn to_tt(
+            tree: &BuildTree,
+            relevant_output: &FileStamp,
+            printed_jobs: &mut BTreeSet<JobSpec>,
+        ) -> termtree::Tree<String> {
             let mut tt = termtree::Tree::new(format!(
                 "{}@{} <= {}{}",
                 relevant_output.path,
@@ -54,31 +104,47 @@ impl fmt::Display for BuildTree {
                 for x in &tree.sources {
                     tt.push(format!("{:#.8}", x));
                 }
-                for (_, job) in &tree.intermediates {
-                    tt.push(to_tt(job, printed_jobs));
+                for (x, job) in &tree.intermediates {
+                    tt.push(to_tt(job, x, printed_jobs));
                 }
             } else {
                 tt.root.push_str(" (see above)");
             }
             tt
         }
-        to_tt(self, &mut printed_jobs).fmt(f)
+        // The job may have side outputs, but it's the target we care about
+        let output = self
+            .outputs
+            .iter()
+            .find(|x| x.path == self.job.target)
+            .unwrap_or(&self.outputs[0]);
+        to_tt(self, output, &mut printed_jobs).fmt(f)
     }
 }
 
 #[derive(Debug, Default)]
 pub struct DepGraph {
-    pub traces: BTreeMap<JobSpec, HashSet<Trace>>,
+    /// Traces are keyed by the hash of their tracefile
+    pub traces: BTreeMap<JobSpec, HashMap<Hash, Trace>>,
 }
 
 impl DepGraph {
+    /// Load every trace in the store, except those which aren't signed by a
+    /// trusted key (if `redux.trustedKey` is set)
     pub fn load_all() -> anyhow::Result<Self> {
-        let mut traces: BTreeMap<JobSpec, HashSet<Trace>> = BTreeMap::default();
+        let trust = TrustList::from_config();
+        let mut graph = DepGraph::default();
         for dent in std::fs::read_dir(&*TRACES_DIR)? {
-            let (job, trace) = TraceFile::read(&dent?.path())?;
-            traces.entry(job).or_default().insert(trace);
+            let path = dent?.path();
+            let Some(hash) = index::parse_trace_path(&path) else {
+                warn!("{}: Not a tracefile; skipping", path.display());
+                continue;
+            };
+            let Some((job, trace)) = TraceFile::read_trusted(&path, &trust)? else {
+                continue;
+            };
+            graph.traces.entry(job).or_default().insert(hash, trace);
         }
-        let graph = DepGraph { traces };
         debug!(
             "Loaded {} traces from {}",
             graph.len(),
@@ -87,16 +153,55 @@ impl DepGraph {
         Ok(graph)
     }
 
-    pub fn load(ruleset: &RuleSet) -> anyhow::Result<Self> {
+    pub fn load() -> anyhow::Result<Self> {
         let mut graph = Self::load_all()?;
-        graph.drop_superseded(ruleset);
+        graph.drop_superseded();
         Ok(graph)
     }
 
-    /// Drop if the rule has been overridden by a new, higher-priority rule
-    pub fn drop_superseded(&mut self, ruleset: &RuleSet) {
+    /// Load only the traces which could be relevant to `job`: its own traces,
+    /// plus (recursively) the traces which produced its intermediates.  These
+    /// are found via the indexes, so this doesn't need to read the whole trace
+    /// store.  Traces from superseded rules aren't dropped here, since that
+    /// depends on which checkout they're validated against.
+    pub fn load_for(job: &JobSpec) -> anyhow::Result<Self> {
+        let trust = TrustList::from_config();
+        let mut graph = DepGraph::default();
+        let mut todo = index::traces_for_job(job)?
+            .into_iter()
+            .map(|hash| (job.clone(), hash))
+            .collect::<Vec<_>>();
+        while let Some((job, hash)) = todo.pop() {
+            if graph.get(&job, hash).is_some() {
+                continue;
+            }
+            let Some((_, trace)) = TraceFile::read_trusted(&index::trace_path(hash), &trust)?
+            else {
+                continue;
+            };
+            for x in &trace.intermediates {
+                todo.extend(index::traces_producing(x.hash)?);
+            }
+            graph.traces.entry(job).or_default().insert(hash, trace);
+        }
+        debug!("Loaded {} traces relevant to {job}", graph.len());
+        Ok(graph)
+    }
+
+    fn get(&self, job: &JobSpec, hash: Hash) -> Option<(&JobSpec, &Trace)> {
+        let (job, ts) = self.traces.get_key_value(job)?;
+        Some((job, ts.get(&hash)?))
+    }
+
+    /// Drop if the rule has been overridden by a new, higher-priority rule.
+    /// Traces which record the dofiles that would override their rule (as
+    /// `absent` lines) are kept: validating them checks this anyway.
+    pub fn drop_superseded(&mut self) {
         let n = self.len();
-        self.traces.retain(|j, _| ruleset.is_job_valid(j));
+        self.traces.retain(|j, ts| {
+            ts.retain(|_, t| t.records_candidates(j) || RuleSet::is_job_current(j));
+            !ts.is_empty()
+        });
         debug!(
             "Dropped {} trace(s) produced by superseded rules",
             n - self.len()
@@ -106,7 +211,7 @@ impl DepGraph {
     pub fn drop_out_of_date(&mut self) {
         let n = self.len();
         self.traces.retain(|_, ts| {
-            ts.retain(|t| {
+            ts.retain(|_, t| {
                 // Drop if any of the sources are out-of-date
                 t.sources.iter().all(|s| s.is_valid().unwrap())
             });
@@ -128,9 +233,10 @@ impl DepGraph {
     }
 
     pub fn some_tree_for(&self, target: &FileStamp) -> Option<BuildTree> {
-        let (job, trace) = self.runs_producing(target).next()?;
+        let (job, hash, trace) = self.runs_producing(target).into_iter().next()?;
         let mut tree = BuildTree {
             job: job.clone(),
+            trace: hash,
             sources: trace.sources.clone(),
             intermediates: Vec::with_capacity(trace.intermediates.len()), // We'll fill this in next
             outputs: trace.outputs.clone(),
@@ -146,7 +252,16 @@ impl DepGraph {
 
     // TODO: Avoid checking the same trace multiple times
     // TODO: Protect against stack overflows
-    fn is_trace_valid(&self, job: &JobSpec, trace: &Trace) -> Option<BuildTree> {
+    fn is_trace_valid(
+        &self,
+        at: &dyn Checkout,
+        job: &JobSpec,
+        hash: Hash,
+        trace: &Trace,
+    ) -> Option<BuildTree> {
+        if trace.unparseable_lines > 0 {
+            return None;
+        }
         if trace.valid_until.is_some_and(|t| t < SystemTime::now()) {
             return None;
         }
@@ -155,11 +270,43 @@ impl DepGraph {
                 return None;
             }
         }
-        if !trace.sources.iter().all(|x| x.is_valid().unwrap_or(false)) {
+        // If the trace records the dofiles which would take over from its
+        // rule then the `absent` check below covers this
+        if !trace.records_candidates(job) && !at.is_job_current(job) {
+            return None;
+        }
+        if !trace.sources.iter().all(|x| at.is_source_valid(trace, x)) {
+            return None;
+        }
+        if !trace.globs.is_empty() {
+            let own_outputs = trace
+                .outputs
+                .iter()
+                .map(|x| x.path.clone())
+                .chain([job.target.clone()])
+                .collect::<Vec<_>>();
+            if !trace
+                .globs
+                .iter()
+                .all(|x| at.is_glob_valid(x, &own_outputs))
+            {
+                return None;
+            }
+        }
+        if trace.absent.iter().any(|x| at.exists(x)) {
+            return None;
+        }
+        if let Some(x) = &trace.interpreter {
+            if at.interpreter(&job.rule).as_ref() != Some(x) {
+                return None;
+            }
+        }
+        if trace.capture_stdout != config::capture_stdout(&job.rule) {
             return None;
         }
         let mut tree = BuildTree {
             job: job.clone(),
+            trace: hash,
             sources: trace.sources.clone(),
             intermediates: Vec::with_capacity(trace.intermediates.len()), // We'll fill this in next
             outputs: trace.outputs.clone(),
@@ -168,33 +315,66 @@ impl DepGraph {
         for x in &trace.intermediates {
             let witness = self
                 .runs_producing(x)
-                .find_map(|(job, trace)| self.is_trace_valid(job, trace))?;
+                .into_iter()
+                .find_map(|(job, hash, trace)| self.is_trace_valid(at, job, hash, trace))?;
             tree.intermediates.push((x.clone(), witness));
         }
         Some(tree)
     }
 
     pub fn valid_trace_for(&self, job: &JobSpec) -> Option<BuildTree> {
+        self.valid_trace_at(&CurrentWorktree, job)
+    }
+
+    /// Like `valid_trace_for()`, but checks the trace's dependencies against
+    /// `at` rather than the current worktree
+    pub fn valid_trace_at(&self, at: &dyn Checkout, job: &JobSpec) -> Option<BuildTree> {
         self.traces
             .get(job)
             .into_iter()
-            .flatten()
-            .find_map(|t| self.is_trace_valid(job, t))
+            .flat_map(|ts| ts.iter())
+            .find_map(|(hash, t)| self.is_trace_valid(at, job, *hash, t))
+    }
+
+    /// Everything which would be restored by a currently-valid trace,
+    /// including the intermediates needed to validate it
+    pub fn live_outputs(&self) -> HashSet<FileStamp> {
+        fn go(tree: &BuildTree, live: &mut HashSet<FileStamp>) {
+            live.extend(tree.outputs.iter().cloned());
+            for (_, tree) in &tree.intermediates {
+                go(tree, live);
+            }
+        }
+        let mut live = HashSet::default();
+        for job in self.traces.keys() {
+            if let Some(tree) = self.valid_trace_for(job) {
+                go(&tree, &mut live);
+            }
+        }
+        live
     }
 
-    // TODO: We could just use the ruleset and jump to the relevant job
-    fn runs_producing<'a>(
-        &'a self,
-        file: &'a FileStamp,
-    ) -> impl Iterator<Item = (&'a JobSpec, &'a Trace)> + 'a {
-        self.all_traces()
-            .filter(move |(_, t)| t.outputs.iter().any(|x| x == file))
+    /// Uses the output index to jump straight to the relevant traces.  Only
+    /// traces which are part of this graph are returned.
+    fn runs_producing<'a>(&'a self, file: &FileStamp) -> Vec<(&'a JobSpec, Hash, &'a Trace)> {
+        let candidates = match index::traces_producing(file.hash) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("{file}: Couldn't read the output index: {e:#}");
+                return vec![];
+            }
+        };
+        candidates
+            .into_iter()
+            .filter_map(|(job, hash)| self.get(&job, hash).map(|(job, t)| (job, hash, t)))
+            .filter(|(_, _, t)| t.outputs.contains(file))
+            .collect()
     }
 
     fn all_traces(&self) -> impl Iterator<Item = (&JobSpec, &Trace)> {
         self.traces
             .iter()
-            .flat_map(|(job, ts)| ts.iter().map(move |t| (job, t)))
+            .flat_map(|(job, ts)| ts.values().map(move |t| (job, t)))
     }
 
     /// May contain duplicates
@@ -1,14 +1,73 @@
-use crate::local_path::LocalPath;
+use crate::{hash_cache, local_path::LocalPath, tree};
 use anyhow::{anyhow, Context};
 use blake3::Hash;
 use std::fmt;
-use std::path::PathBuf;
+use std::fs::Metadata;
+use std::os::unix::{ffi::OsStringExt, fs::PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone)]
 pub struct FileStamp {
     pub path: LocalPath,
     pub hash: Hash,
+    pub kind: FileKind,
+}
+
+/// Like git, we only track whether a file is executable, not its full mode.
+/// Other permission bits depend on the user's umask, and tracking them would
+/// cause spurious rebuilds.
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
+pub enum FileKind {
+    /// The hash is the hash of the file's contents
+    File,
+    /// Like `File`, but the file is executable
+    Exec,
+    /// The hash is the hash of the path which the link points to
+    Symlink,
+    /// The hash is the hash of the directory's tree object.  See `tree`.
+    Dir,
+}
+
+impl FileKind {
+    const ALL: [FileKind; 4] = [
+        FileKind::File,
+        FileKind::Exec,
+        FileKind::Symlink,
+        FileKind::Dir,
+    ];
+
+    /// Written between the "@" and the hash.  Plain files have no prefix, for
+    /// compatibility with tracefiles recorded before other kinds were
+    /// supported.
+    fn prefix(self) -> &'static str {
+        match self {
+            FileKind::File => "",
+            FileKind::Exec => "exec:",
+            FileKind::Symlink => "link:",
+            FileKind::Dir => "tree:",
+        }
+    }
+
+    pub fn of(meta: &Metadata) -> FileKind {
+        if meta.is_symlink() {
+            FileKind::Symlink
+        } else if meta.is_dir() {
+            FileKind::Dir
+        } else if meta.permissions().mode() & 0o111 != 0 {
+            FileKind::Exec
+        } else {
+            FileKind::File
+        }
+    }
+
+    /// The mode to give a restored file of this kind
+    pub fn mode(self) -> u32 {
+        match self {
+            FileKind::Exec | FileKind::Dir => 0o755,
+            FileKind::File | FileKind::Symlink => 0o644,
+        }
+    }
 }
 
 impl fmt::Display for FileStamp {
@@ -16,14 +75,15 @@ impl fmt::Display for FileStamp {
         use yansi::Paint;
         let hash = self.hash.to_hex();
         let hash = f.precision().map(|x| &hash[..x]).unwrap_or(&hash);
+        let prefix = self.kind.prefix();
         if f.alternate() {
             match self.is_valid() {
-                Ok(true) => write!(f, "{}@{}", self.path, hash.green()),
-                Ok(false) => write!(f, "{}@{}", self.path, hash.red()),
-                Err(_) => write!(f, "{}@{}", self.path.red(), hash),
+                Ok(true) => write!(f, "{}@{prefix}{}", self.path, hash.green()),
+                Ok(false) => write!(f, "{}@{prefix}{}", self.path, hash.red()),
+                Err(_) => write!(f, "{}@{prefix}{}", self.path.red(), hash),
             }
         } else {
-            write!(f, "{}@{}", self.path, hash)
+            write!(f, "{}@{prefix}{}", self.path, hash)
         }
     }
 }
@@ -32,10 +92,16 @@ impl FromStr for FileStamp {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (path, hash) = s.split_once('@').ok_or_else(|| anyhow!("No @ sign"))?;
+        let (path, hash) = s.rsplit_once('@').ok_or_else(|| anyhow!("No @ sign"))?;
+        let (kind, hash) = FileKind::ALL
+            .into_iter()
+            .rev() // File's prefix is empty, so it has to go last
+            .find_map(|kind| Some((kind, hash.strip_prefix(kind.prefix())?)))
+            .unwrap();
         Ok(FileStamp {
             path: path.parse()?,
             hash: hash.parse()?,
+            kind,
         })
     }
 }
@@ -48,18 +114,21 @@ impl PartialOrd for FileStamp {
 
 impl Ord for FileStamp {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        (&self.path, self.hash.as_bytes()).cmp(&(&other.path, other.hash.as_bytes()))
+        (&self.path, self.kind, self.hash.as_bytes()).cmp(&(
+            &other.path,
+            other.kind,
+            other.hash.as_bytes(),
+        ))
     }
 }
 
 impl FileStamp {
+    /// Stamp a file, symlink, or directory.  Symlinks are not followed.  The
+    /// hash of a directory is computed from the hashes of its contents; see
+    /// `tree`.
     pub fn new(path: LocalPath) -> anyhow::Result<Self> {
-        let mut hasher = blake3::Hasher::new();
-        hasher
-            .update_mmap_rayon(path.to_abs())
-            .context(path.to_string())?;
-        let hash = hasher.finalize();
-        Ok(FileStamp { path, hash })
+        let (kind, hash) = hash_path(&path.to_abs()).context(path.to_string())?;
+        Ok(FileStamp { path, hash, kind })
     }
 
     pub fn abs_path(&self) -> PathBuf {
@@ -67,9 +136,33 @@ impl FileStamp {
     }
 
     pub fn is_valid(&self) -> anyhow::Result<bool> {
-        let mut hasher = blake3::Hasher::new();
-        hasher.update_mmap_rayon(self.abs_path())?;
-        let hash = hasher.finalize();
-        Ok(hash == self.hash)
+        let (kind, hash) = hash_path(&self.abs_path())?;
+        Ok(kind == self.kind && hash == self.hash)
     }
 }
+
+fn hash_path(path: &Path) -> anyhow::Result<(FileKind, Hash)> {
+    let meta = std::fs::symlink_metadata(path)?;
+    let kind = FileKind::of(&meta);
+    let hash = match kind {
+        FileKind::File | FileKind::Exec => hash_cache::hash_file(path, &meta)?,
+        FileKind::Symlink => blake3::hash(&read_symlink(path)?),
+        FileKind::Dir => tree::hash_dir(path, &mut |_| Ok(()))?,
+    };
+    Ok((kind, hash))
+}
+
+/// The raw bytes of a symlink's target.  This is what gets hashed and stored.
+pub fn read_symlink(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let target =
+        std::fs::read_link(path).with_context(|| format!("Reading symlink {}", path.display()))?;
+    Ok(target.into_os_string().into_vec())
+}
+
+/// Hash a file's contents.  Most callers should use `hash_cache::hash_file()`
+/// instead, to avoid rehashing unchanged files.
+pub fn hash_file(path: &Path) -> anyhow::Result<Hash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_mmap_rayon(path)?;
+    Ok(hasher.finalize())
+}
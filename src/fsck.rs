@@ -0,0 +1,148 @@
+use crate::{
+    artifacts::{Blob, Encoding},
+    index, redux_dir,
+    signing::{SignatureStatus, TrustList},
+    Artifacts, TraceFile, TRACES_DIR,
+};
+use anyhow::Context;
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+use tracing::{debug, info};
+
+/// Where `fsck()` moves corrupt entries, if asked to.  Mirrors the layout of
+/// the redux dir.
+pub static QUARANTINE_DIR: LazyLock<PathBuf> = LazyLock::new(|| redux_dir().join("quarantine"));
+
+/// What `fsck()` checked and found
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FsckStats {
+    pub artifacts: usize,
+    pub traces: usize,
+    /// Corrupt artifacts and traces
+    pub corrupt: usize,
+    /// Traces whose outputs are missing from the artifact store.  This isn't
+    /// necessarily a problem: the artifacts may have been evicted.
+    pub missing_outputs: usize,
+    /// Traces which will be ignored because they aren't signed by a trusted
+    /// key.  Only counted if `redux.trustedKey` is set.
+    pub untrusted: usize,
+}
+
+/// Check the integrity of the database:
+///
+/// * every artifact should hash to its name;
+/// * every trace should be named after its own hash, be parsable, and (if it's
+///   signed) have a valid signature;
+/// * if `redux.trustedKey` is set, every trace should be signed by a trusted
+///   key; and
+/// * the outputs of every trace (including the full contents of any output
+///   directories) should be in the artifact store.
+///
+/// Problems are printed to stdout.  If `quarantine` is set, corrupt artifacts
+/// and traces are moved to `QUARANTINE_DIR`.
+pub fn fsck(quarantine: bool) -> anyhow::Result<FsckStats> {
+    let mut stats = FsckStats::default();
+    let artifacts = Artifacts::new()?;
+    let trust = TrustList::from_config();
+
+    for blob in artifacts.blobs()? {
+        stats.artifacts += 1;
+        match hash_blob(&blob) {
+            Ok(x) if x == blob.hash => continue,
+            Ok(x) => println!(
+                "{}: Contents have hash {x}, but it's named after {}",
+                blob.path.display(),
+                blob.hash,
+            ),
+            Err(e) => println!("{}: {e:#}", blob.path.display()),
+        }
+        stats.corrupt += 1;
+        if quarantine {
+            move_to_quarantine(&blob.path)?;
+        }
+    }
+
+    for dent in std::fs::read_dir(&*TRACES_DIR)? {
+        let path = dent?.path();
+        // Eg. an in-flight import, which will be renamed into place shortly
+        if index::parse_trace_path(&path).is_none() {
+            continue;
+        }
+        stats.traces += 1;
+        let txt = std::fs::read(&path).with_context(|| format!("Reading {}", path.display()))?;
+        let mut problems = vec![];
+
+        let hash = blake3::hash(&txt);
+        if path.file_name() != Some(format!("{hash}.trace").as_ref()) {
+            problems.push(format!("Contents have hash {hash}"));
+        }
+        match trust.check(&txt) {
+            SignatureStatus::BadSignature => problems.push("Bad signature".into()),
+            status if trust.is_configured() && !trust.accepts(status) => {
+                println!("{}: {status}; it will be ignored", path.display());
+                stats.untrusted += 1;
+            }
+            _ => (),
+        }
+        let parsed = std::str::from_utf8(&txt)
+            .map_err(anyhow::Error::from)
+            .and_then(TraceFile::parse);
+        match parsed {
+            Ok((job, trace, errors)) => {
+                problems.extend(errors.into_iter().map(|e| format!("{e:#}")));
+                let missing = trace
+                    .outputs
+                    .iter()
+                    .filter(|x| !artifacts.contains_stamp(x))
+                    .collect::<Vec<_>>();
+                if !missing.is_empty() {
+                    stats.missing_outputs += 1;
+                }
+                for x in missing {
+                    println!("{}: {job}: Output {x} is not in the store", path.display());
+                }
+            }
+            Err(e) => problems.push(format!("{e:#}")),
+        }
+
+        if problems.is_empty() {
+            continue;
+        }
+        for x in problems {
+            println!("{}: {x}", path.display());
+        }
+        stats.corrupt += 1;
+        if quarantine {
+            move_to_quarantine(&path)?;
+        }
+    }
+
+    Ok(stats)
+}
+
+fn hash_blob(blob: &Blob) -> anyhow::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    match blob.encoding {
+        Encoding::Plain => {
+            hasher.update_mmap_rayon(&blob.path)?;
+        }
+        Encoding::Zstd => {
+            let input = File::open(&blob.path)?;
+            zstd::stream::copy_decode(input, &mut hasher).context("Decompressing")?;
+        }
+    }
+    debug!("{}: Rehashed", blob.path.display());
+    Ok(hasher.finalize())
+}
+
+fn move_to_quarantine(path: &Path) -> anyhow::Result<()> {
+    let rel = path.strip_prefix(redux_dir())?;
+    let to = QUARANTINE_DIR.join(rel);
+    std::fs::create_dir_all(to.parent().unwrap())?;
+    std::fs::rename(path, &to).with_context(|| format!("Quarantining {}", path.display()))?;
+    info!("{}: Moved to {}", path.display(), to.display());
+    Ok(())
+}
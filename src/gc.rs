@@ -0,0 +1,171 @@
+use crate::{
+    config, index, rev, signing::TrustList, stats::STATS_DIR, trace::TraceFile, Artifacts, BuildId,
+    RuleSet, BUILDS_DIR, TRACES_DIR,
+};
+use anyhow::Context;
+use blake3::Hash;
+use std::{
+    collections::HashSet,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+use tracing::{debug, info, warn};
+
+/// How long to keep the stats of finished builds
+const STATS_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// What `gc()` removed (or would have removed, in a dry run)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GcStats {
+    pub traces: usize,
+    pub artifacts: usize,
+    pub bytes: u64,
+}
+
+/// Remove traces which can never be used again, and then any artifacts which
+/// aren't referenced by the remaining traces.
+///
+/// A trace is garbage if:
+///
+/// * it has a `valid_until` in the past;
+/// * it has a `valid_for` and that build has finished; or
+/// * its job uses a rule which has been superseded by a higher-priority rule.
+///   The redux dir is shared by all worktrees (including the scratch ones
+///   used by `--rev`), so this means superseded in every one of them.
+///
+/// Stats of builds which finished more than `STATS_MAX_AGE` ago are removed
+/// too, as are scratch worktrees left behind by killed `--rev` runs.
+///
+/// Finally, if `redux.maxArtifactsSize` is set, the artifact store is brought
+/// back within budget.
+pub fn gc(dry_run: bool) -> anyhow::Result<GcStats> {
+    if !dry_run {
+        rev::remove_stale_worktrees()?;
+    }
+    let worktrees = rev::worktrees()?;
+    let now = SystemTime::now();
+    let mut stats = GcStats::default();
+
+    let artifacts = Artifacts::new()?;
+    let mut live = HashSet::<Hash>::default();
+    let trust = TrustList::from_config();
+    for dent in std::fs::read_dir(&*TRACES_DIR)? {
+        let path = dent?.path();
+        // Skip in-flight imports and the like
+        if index::parse_trace_path(&path).is_none() {
+            continue;
+        }
+        let (job, trace) = match TraceFile::read_trusted(&path, &trust) {
+            Ok(Some(x)) => x,
+            // Its outputs won't be used, so they aren't kept alive.  The trace
+            // itself is kept, in case it becomes trusted later.
+            Ok(None) => continue,
+            Err(e) => {
+                // It may have been removed by a concurrent gc, or be corrupt
+                // (which is fsck's business)
+                warn!("{e:#}");
+                continue;
+            }
+        };
+        let reason = if trace.valid_until.is_some_and(|t| t < now) {
+            Some("expired")
+        } else if trace.valid_for.is_some_and(|id| id.is_finished()) {
+            Some("volatile, and its build has finished")
+        } else if !worktrees
+            .iter()
+            .any(|x| RuleSet::is_job_current_in(x, &job))
+        {
+            Some("rule has been superseded")
+        } else {
+            None
+        };
+        match reason {
+            Some(reason) => {
+                info!("{}: Removing trace ({reason})", path.display());
+                stats.bytes += remove(&path, dry_run)?;
+                stats.traces += 1;
+            }
+            None => {
+                for x in trace
+                    .sources
+                    .iter()
+                    .chain(&trace.intermediates)
+                    .chain(&trace.outputs)
+                {
+                    // If a tree object is missing, its contents can't be
+                    // restored anyway
+                    live.extend(artifacts.closure(x.kind, x.hash).unwrap_or(vec![x.hash]));
+                }
+            }
+        }
+    }
+
+    // NOTE: A concurrent build may insert an artifact and only later commit
+    // the trace which references it.  Skip anything which appeared after we
+    // started, so we don't pull the rug out from under it.
+    for blob in artifacts.blobs()? {
+        if live.contains(&blob.hash) || std::fs::metadata(&blob.path)?.modified()? >= now {
+            continue;
+        }
+        info!("{}: Removing unreferenced artifact", blob.path.display());
+        stats.bytes += remove(&blob.path, dry_run)?;
+        stats.artifacts += 1;
+    }
+
+    for dent in std::fs::read_dir(&*BUILDS_DIR)? {
+        let path = dent?.path();
+        let Some(id) = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.parse().ok())
+            .map(BuildId)
+        else {
+            continue;
+        };
+        if id.is_finished() {
+            debug!("{}: Removing lockfile of finished build", path.display());
+            remove(&path, dry_run)?;
+        }
+    }
+
+    for dent in std::fs::read_dir(&*STATS_DIR)? {
+        let path = dent?.path();
+        let Some(id) = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.parse().ok())
+            .map(BuildId)
+        else {
+            continue;
+        };
+        let age = std::fs::metadata(&path)?
+            .modified()?
+            .elapsed()
+            .unwrap_or_default();
+        if age > STATS_MAX_AGE && id.is_finished() {
+            debug!("{}: Removing old build stats", path.display());
+            remove(&path, dry_run)?;
+        }
+    }
+
+    if !dry_run && stats.traces > 0 {
+        index::rebuild()?;
+    }
+
+    if let Some(budget) = config::max_artifacts_size().filter(|_| !dry_run) {
+        stats.bytes += Artifacts::new()?.enforce_budget(budget)?;
+    }
+
+    Ok(stats)
+}
+
+/// Returns the number of bytes freed
+fn remove(path: &Path, dry_run: bool) -> anyhow::Result<u64> {
+    let len = std::fs::symlink_metadata(path)
+        .with_context(|| format!("Reading metadata of {}", path.display()))?
+        .len();
+    if !dry_run {
+        std::fs::remove_file(path).with_context(|| format!("Removing {}", path.display()))?;
+    }
+    Ok(len)
+}
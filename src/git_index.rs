@@ -0,0 +1,62 @@
+//! If `redux.gitIndex` is set, traces record the git blob ID of each checked-in
+//! source alongside its stamp.  Git's index already remembers the blob ID and
+//! stat data of every tracked file, so if it says the file is clean and still
+//! has that blob ID, we know its contents haven't changed without reading it.
+//!
+//! Like git, we don't trust the index's stat data for files which were modified
+//! around the time the index was written (see "racy git").
+
+use crate::{config, FileKind, FileStamp, LocalPath, TraceFileLine, REPO};
+use gix::{
+    index::entry::{stat, Mode, Stat},
+    ObjectId,
+};
+use std::sync::LazyLock;
+use tracing::debug;
+
+static INDEX: LazyLock<Option<gix::worktree::Index>> = LazyLock::new(|| {
+    if !config::git_index() {
+        return None;
+    }
+    match REPO.to_thread_local().index() {
+        Ok(x) => Some(x),
+        Err(e) => {
+            debug!("Not using the git index: {e}");
+            None
+        }
+    }
+});
+
+/// The blob ID of `path`, if it's tracked and git's index says it's clean
+fn clean_blob(path: &LocalPath) -> Option<ObjectId> {
+    let index = INDEX.as_ref()?;
+    let key = gix::bstr::BStr::new(path.as_path().to_str()?.as_bytes());
+    let entry = index.entry_by_path(key)?;
+    if !matches!(entry.mode, Mode::FILE | Mode::FILE_EXECUTABLE) {
+        return None;
+    }
+    let meta = gix::index::fs::Metadata::from_path_no_follow(&path.to_abs()).ok()?;
+    let current = Stat::from_fs(&meta).ok()?;
+    let opts = stat::Options::default();
+    if entry.stat.is_racy(index.timestamp(), opts) || !entry.stat.matches(&current, opts) {
+        return None;
+    }
+    Some(entry.id)
+}
+
+/// A line recording the blob ID of a source, if there's one to record.  Call
+/// this right after stamping the file.
+pub fn blob_line(source: &FileStamp) -> Option<TraceFileLine> {
+    let id = clean_blob(&source.path)?;
+    Some(TraceFileLine::GitBlob(source.path.clone(), id))
+}
+
+/// Whether git's index says that `source` still has the blob ID `id`.  `false`
+/// means we don't know, not that it's changed.
+pub fn is_unchanged(source: &FileStamp, id: ObjectId) -> bool {
+    let Ok(meta) = std::fs::symlink_metadata(source.abs_path()) else {
+        return false;
+    };
+    // The blob ID only covers the contents; the exec bit is part of the stamp
+    FileKind::of(&meta) == source.kind && clean_blob(&source.path) == Some(id)
+}
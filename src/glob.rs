@@ -0,0 +1,156 @@
+//! Dependencies on the set of files matching a glob, so that adding or removing
+//! a matching file invalidates the trace.  The pattern is stored relative to
+//! the top of the worktree, along with the hash of the (sorted) list of paths
+//! it matched.  Note that this only covers _which_ files match: their contents
+//! are tracked separately, as ordinary sources.
+
+use crate::{local_path::project_base, LocalPath};
+use anyhow::{anyhow, bail, ensure, Context};
+use blake3::Hash;
+use globset::{GlobBuilder, GlobMatcher};
+use std::{fmt, str::FromStr};
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub struct GlobStamp {
+    pub pattern: String,
+    pub hash: Hash,
+}
+
+impl fmt::Display for GlobStamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.hash, self.pattern)
+    }
+}
+
+impl FromStr for GlobStamp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (hash, pattern) = s.split_once(' ').ok_or_else(|| anyhow!("No pattern"))?;
+        Ok(GlobStamp {
+            pattern: pattern.to_owned(),
+            hash: hash.parse()?,
+        })
+    }
+}
+
+impl GlobStamp {
+    /// Expand the pattern, returning the stamp along with the matching paths.
+    /// Paths in `exclude` (the job's own outputs) are left out of both.
+    pub fn new(pattern: String, exclude: &[LocalPath]) -> anyhow::Result<(Self, Vec<LocalPath>)> {
+        let mut paths = expand(&pattern)?;
+        paths.retain(|x| !exclude.contains(x));
+        let hash = hash_paths(&paths);
+        Ok((GlobStamp { pattern, hash }, paths))
+    }
+
+    /// `exclude` should be the job's outputs, as passed to `new()`
+    pub fn is_valid(&self, exclude: &[LocalPath]) -> anyhow::Result<bool> {
+        let mut paths = expand(&self.pattern)?;
+        paths.retain(|x| !exclude.contains(x));
+        Ok(hash_paths(&paths) == self.hash)
+    }
+
+    /// Like `is_valid()`, but checks against `files` (sorted) rather than
+    /// what's in the worktree
+    pub fn is_valid_for(&self, files: &[LocalPath], exclude: &[LocalPath]) -> anyhow::Result<bool> {
+        let matcher = matcher(&self.pattern)?;
+        let paths = files
+            .iter()
+            .filter(|x| matcher.is_match(x.as_path()) && !exclude.contains(x))
+            .cloned()
+            .collect::<Vec<_>>();
+        Ok(hash_paths(&paths) == self.hash)
+    }
+}
+
+/// A pattern matching every file under `dir`, recursively
+pub fn dir_pattern(dir: &LocalPath) -> anyhow::Result<String> {
+    anchor_pattern(dir, "**")
+}
+
+/// Make a pattern which is relative to `dir` relative to the top of the
+/// worktree instead.  `.` and `..` are resolved, so that the stored pattern is
+/// the same however it was written; patterns which reach outside the worktree
+/// are rejected.
+pub fn anchor_pattern(dir: &LocalPath, pattern: &str) -> anyhow::Result<String> {
+    ensure!(!pattern.starts_with('/'), "{pattern}: Must be relative");
+    ensure!(
+        !dir.as_path().starts_with(".."),
+        "{dir}: Outside the worktree"
+    );
+    // Each component, and whether it can be popped by a `..`
+    let mut components = dir
+        .as_path()
+        .iter()
+        .map(|x| Ok((globset::escape(x.to_str().context("Non-UTF-8 path")?), true)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    for x in pattern.split('/') {
+        match x {
+            "" | "." => (),
+            ".." => match components.pop() {
+                Some((_, true)) => (),
+                Some((_, false)) => bail!("{pattern}: `..` can't follow a wildcard"),
+                None => bail!("{pattern}: Reaches outside the worktree"),
+            },
+            x => components.push((x.to_owned(), !x.contains(['*', '?', '[', '{', '\\']))),
+        }
+    }
+    ensure!(!components.is_empty(), "{pattern}: Empty pattern");
+    Ok(components
+        .into_iter()
+        .map(|(x, _)| x)
+        .collect::<Vec<_>>()
+        .join("/"))
+}
+
+/// The files (and symlinks) matching `pattern`, sorted.  `*` doesn't match
+/// `/`, but `**` does.  The git dir and redux's temporary files are skipped.
+pub fn expand(pattern: &str) -> anyhow::Result<Vec<LocalPath>> {
+    let matcher = matcher(pattern)?;
+    // Only walk the part of the tree which could possibly match
+    let prefix = pattern
+        .split('/')
+        .take_while(|x| !x.contains(['*', '?', '[', '{', '\\']))
+        .collect::<Vec<_>>()
+        .join("/");
+    let root = project_base().join(prefix);
+    if !root.exists() {
+        return Ok(vec![]);
+    }
+    let mut paths = vec![];
+    let walk = walkdir::WalkDir::new(&root).into_iter().filter_entry(|x| {
+        let name = x.file_name().to_str().unwrap_or("");
+        name != ".git" && !name.starts_with(".redux_")
+    });
+    for ent in walk {
+        let ent = ent?;
+        if ent.file_type().is_dir() {
+            continue;
+        }
+        let path = ent.path().strip_prefix(project_base())?;
+        // Non-UTF-8 paths can't be written into a tracefile anyway
+        let Some(path) = path.to_str() else { continue };
+        if matcher.is_match(path) {
+            paths.push(path.parse()?);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn matcher(pattern: &str) -> anyhow::Result<GlobMatcher> {
+    Ok(GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher())
+}
+
+fn hash_paths(paths: &[LocalPath]) -> Hash {
+    let mut hasher = blake3::Hasher::new();
+    for x in paths {
+        hasher.update(x.to_string().as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher.finalize()
+}
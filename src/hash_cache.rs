@@ -0,0 +1,189 @@
+//! A cache of file hashes, so that we don't have to rehash files which haven't
+//! changed.  Like git's index, it's keyed on the file's path, and remembers the
+//! inode, size, mtime, and ctime which the file had when it was hashed.  If
+//! they all still match, the file is assumed not to have changed.
+//!
+//! The cache lives in `redux_dir/hashcache`.  It's an append-only log, with one
+//! entry per line:
+//!
+//! ```text
+//! <hash> <inode> <size> <mtime> <ctime> <path>
+//! ```
+//!
+//! where the times are in nanoseconds since the epoch.  Later entries override
+//! earlier ones.  The log is compacted when it gets too long.
+//!
+//! A file which is modified twice in quick succession may end up with the same
+//! size and timestamps both times (git calls this "racily clean").  To guard
+//! against this, a file is only added to the cache once its timestamps are
+//! safely in the past.
+
+use crate::{filestamp, redux_dir};
+use blake3::Hash;
+use std::{
+    collections::HashMap,
+    fs::{File, Metadata},
+    io::Write,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+    time::{Duration, SystemTime},
+};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// Files whose timestamps are more recent than this aren't cached
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// The parts of a file's metadata which change when it's modified
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stat {
+    ino: u64,
+    size: u64,
+    mtime: i64,
+    ctime: i64,
+}
+
+impl Stat {
+    fn of(meta: &Metadata) -> Stat {
+        Stat {
+            ino: meta.ino(),
+            size: meta.size(),
+            mtime: meta.mtime() * 1_000_000_000 + meta.mtime_nsec(),
+            ctime: meta.ctime() * 1_000_000_000 + meta.ctime_nsec(),
+        }
+    }
+
+    fn is_racy(&self) -> bool {
+        let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) else {
+            return true;
+        };
+        let cutoff = now.saturating_sub(RACY_WINDOW).as_nanos() as i64;
+        self.mtime.max(self.ctime) >= cutoff
+    }
+}
+
+struct HashCache {
+    path: PathBuf,
+    entries: HashMap<PathBuf, (Stat, Hash)>,
+    log: Option<File>,
+}
+
+static CACHE: LazyLock<Mutex<HashCache>> = LazyLock::new(|| Mutex::new(HashCache::load()));
+
+/// Hash the contents of a regular file, re-using the previous hash if the file
+/// hasn't changed since then.  `meta` should be the file's current metadata.
+pub fn hash_file(path: &Path, meta: &Metadata) -> anyhow::Result<Hash> {
+    let stat = Stat::of(meta);
+    if let Some(hash) = CACHE.lock().unwrap().get(path, stat) {
+        return Ok(hash);
+    }
+    let hash = filestamp::hash_file(path)?;
+    if !stat.is_racy() {
+        CACHE.lock().unwrap().insert(path, stat, hash);
+    }
+    Ok(hash)
+}
+
+impl HashCache {
+    fn load() -> HashCache {
+        let path = redux_dir().join("hashcache");
+        let mut cache = HashCache {
+            path,
+            entries: HashMap::default(),
+            log: None,
+        };
+        let txt = match std::fs::read_to_string(&cache.path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                warn!("{}: {e}", cache.path.display());
+                String::new()
+            }
+        };
+        let mut n_lines = 0;
+        for line in txt.split_inclusive('\n') {
+            n_lines += 1;
+            match line.strip_suffix('\n').and_then(parse_line) {
+                Some((path, stat, hash)) => {
+                    cache.entries.insert(path, (stat, hash));
+                }
+                // Probably a line which was only partially written
+                None => debug!("{}: Skipping bad line", cache.path.display()),
+            }
+        }
+        debug!("Loaded {} cached hashes", cache.entries.len());
+        if n_lines > 2 * cache.entries.len() + 1000 {
+            if let Err(e) = cache.compact() {
+                warn!("Compacting {}: {e:#}", cache.path.display());
+            }
+        }
+        cache
+    }
+
+    fn get(&self, path: &Path, stat: Stat) -> Option<Hash> {
+        let (cached_stat, hash) = self.entries.get(path)?;
+        (*cached_stat == stat).then_some(*hash)
+    }
+
+    fn insert(&mut self, path: &Path, stat: Stat, hash: Hash) {
+        // The log is line-based, so it can't cope with every possible path
+        let Some(path_str) = path.to_str().filter(|x| !x.contains('\n')) else {
+            return;
+        };
+        self.entries.insert(path.to_owned(), (stat, hash));
+        if let Err(e) = self.append(&format_line(path_str, stat, hash)) {
+            warn!("Writing to {}: {e:#}", self.path.display());
+        }
+    }
+
+    fn append(&mut self, line: &str) -> anyhow::Result<()> {
+        let log = match &mut self.log {
+            Some(x) => x,
+            None => self
+                .log
+                .insert(File::options().create(true).append(true).open(&self.path)?),
+        };
+        // Other processes may be appending at the same time.  Writing each
+        // line in one go means they won't be interleaved.
+        log.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// Rewrite the log with just the latest entry for each path.  Entries
+    /// which other processes append while we're doing this may be lost, but
+    /// that's fine: it's only a cache.
+    fn compact(&self) -> anyhow::Result<()> {
+        let tmp = redux_dir().join(format!(".hashcache.{}.tmp", Uuid::new_v4()));
+        let mut txt = String::new();
+        for (path, (stat, hash)) in &self.entries {
+            if let Some(path) = path.to_str() {
+                txt.push_str(&format_line(path, *stat, *hash));
+            }
+        }
+        std::fs::write(&tmp, txt)?;
+        std::fs::rename(&tmp, &self.path)?;
+        debug!("Compacted {}", self.path.display());
+        Ok(())
+    }
+}
+
+fn format_line(path: &str, stat: Stat, hash: Hash) -> String {
+    format!(
+        "{hash} {} {} {} {} {path}\n",
+        stat.ino, stat.size, stat.mtime, stat.ctime,
+    )
+}
+
+fn parse_line(line: &str) -> Option<(PathBuf, Stat, Hash)> {
+    let mut parts = line.splitn(6, ' ');
+    let hash = parts.next()?.parse().ok()?;
+    let stat = Stat {
+        ino: parts.next()?.parse().ok()?,
+        size: parts.next()?.parse().ok()?,
+        mtime: parts.next()?.parse().ok()?,
+        ctime: parts.next()?.parse().ok()?,
+    };
+    let path = PathBuf::from(parts.next()?);
+    Some((path, stat, hash))
+}
@@ -0,0 +1,230 @@
+//! Indexes over the trace store, so that we don't have to read every trace
+//! to find the ones we're interested in.
+//!
+//! * `index/jobs/<hash of JobSpec>` lists the traces recorded for that job.
+//! * `index/outputs/<content hash>` lists the traces which produced a file with
+//!   those contents, along with their jobs.
+//! * `index/paths/<hash of path>` lists the traces which produced a file at
+//!   that path, along with their jobs.
+//!
+//! The indexes are append-only text files, one trace per line.  They may
+//! contain entries for traces which have since been removed (eg. by `--gc`);
+//! readers skip these.  If the index dir is missing, it's rebuilt from the
+//! trace store.
+
+use crate::{
+    redux_dir,
+    trace::{JobSpec, Trace, TraceFile},
+    LocalPath, TRACES_DIR,
+};
+use anyhow::{ensure, Context};
+use blake3::Hash;
+use rustix::fs::{flock, FlockOperation};
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+pub static INDEX_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
+    let path = redux_dir().join("index");
+    if ["jobs", "outputs", "paths"]
+        .iter()
+        .any(|x| !path.join(x).exists())
+    {
+        // We can limp along without an index: lookups will just miss
+        if let Err(e) = rebuild() {
+            warn!("Rebuilding the index: {e:#}");
+        }
+    }
+    path
+});
+
+/// Writers hold this shared, and `rebuild()` holds it exclusively.  It lives
+/// outside the index dir, since `rebuild()` swaps that out.
+fn lock(op: FlockOperation) -> anyhow::Result<File> {
+    let path = redux_dir().join("index.lock");
+    let f = File::create(&path).with_context(|| format!("Creating {}", path.display()))?;
+    flock(&f, op).with_context(|| format!("Flocking {}", path.display()))?;
+    Ok(f)
+}
+
+pub fn trace_path(hash: Hash) -> PathBuf {
+    TRACES_DIR.join(format!("{hash}.trace"))
+}
+
+/// The inverse of `trace_path()`
+pub fn parse_trace_path(path: &Path) -> Option<Hash> {
+    path.file_name()?
+        .to_str()?
+        .strip_suffix(".trace")?
+        .parse()
+        .ok()
+}
+
+pub fn job_key(job: &JobSpec) -> String {
+    blake3::hash(job.to_string().as_bytes()).to_string()
+}
+
+fn path_key(path: &LocalPath) -> String {
+    blake3::hash(path.to_string().as_bytes()).to_string()
+}
+
+/// Record that the trace with the given hash is now in the trace store
+pub fn record(job: &JobSpec, hash: Hash, trace: &Trace) -> anyhow::Result<()> {
+    // Make sure the index exists _before_ taking the lock, since rebuilding it
+    // takes the lock too
+    let dir = &*INDEX_DIR;
+    // Otherwise a concurrent rebuild might miss this trace in its snapshot of
+    // the trace store, and then swap out the index we're appending to
+    let _lock = lock(FlockOperation::LockShared)?;
+    record_in(dir, job, hash, trace)
+}
+
+fn record_in(dir: &Path, job: &JobSpec, hash: Hash, trace: &Trace) -> anyhow::Result<()> {
+    append(&dir.join("jobs").join(job_key(job)), &hash.to_string())?;
+    for x in &trace.outputs {
+        append(
+            &dir.join("outputs").join(x.hash.to_string()),
+            &format!("{hash} {job}"),
+        )?;
+        append(
+            &dir.join("paths").join(path_key(&x.path)),
+            &format!("{hash} {job}"),
+        )?;
+    }
+    Ok(())
+}
+
+/// Add a trace from somewhere else (eg. a remote cache) to the trace store,
+/// checking that it has the expected hash
+pub fn import_trace(hash: Hash, txt: &[u8]) -> anyhow::Result<(JobSpec, Trace)> {
+    let actual = blake3::hash(txt);
+    ensure!(actual == hash, "{hash}: Trace has hash {actual}");
+    // Write it atomically, since other processes may be reading the trace
+    // store
+    let tmp = TRACES_DIR.join(format!(".{}.import", Uuid::new_v4()));
+    std::fs::write(&tmp, txt)?;
+    let path = trace_path(hash);
+    std::fs::rename(&tmp, &path)?;
+    let (job, trace) = TraceFile::read(&path)?;
+    record(&job, hash, &trace)?;
+    Ok((job, trace))
+}
+
+/// All traces which have been recorded for `job` and which still exist
+pub fn traces_for_job(job: &JobSpec) -> anyhow::Result<Vec<Hash>> {
+    let mut hashes = vec![];
+    for line in read(&INDEX_DIR.join("jobs").join(job_key(job)))? {
+        let hash = line.parse()?;
+        if !hashes.contains(&hash) {
+            hashes.push(hash);
+        }
+    }
+    Ok(hashes)
+}
+
+/// All traces which produced a file with the given contents, and which still
+/// exist.  Note that the file may not have been produced at the path you're
+/// interested in!
+pub fn traces_producing(contents: Hash) -> anyhow::Result<Vec<(JobSpec, Hash)>> {
+    read_jobs(&INDEX_DIR.join("outputs").join(contents.to_string()))
+}
+
+/// All traces which produced a file at the given path, and which still exist
+pub fn traces_producing_path(path: &LocalPath) -> anyhow::Result<Vec<(JobSpec, Hash)>> {
+    read_jobs(&INDEX_DIR.join("paths").join(path_key(path)))
+}
+
+fn read_jobs(path: &Path) -> anyhow::Result<Vec<(JobSpec, Hash)>> {
+    let mut xs = vec![];
+    for line in read(path)? {
+        let Some((hash, Ok(job))) = line.split_once(' ').map(|(x, y)| (x, y.parse())) else {
+            warn!("{}: Skipping bad line: {line:?}", path.display());
+            continue;
+        };
+        let x = (job, hash.parse()?);
+        if !xs.contains(&x) {
+            xs.push(x);
+        }
+    }
+    Ok(xs)
+}
+
+/// Rebuild the indexes from scratch, based on the contents of the trace store
+pub fn rebuild() -> anyhow::Result<()> {
+    let _lock = lock(FlockOperation::LockExclusive)?;
+    let tmp = redux_dir().join(format!(".index.{}.tmp", Uuid::new_v4()));
+    std::fs::create_dir_all(tmp.join("jobs"))?;
+    std::fs::create_dir_all(tmp.join("outputs"))?;
+    std::fs::create_dir_all(tmp.join("paths"))?;
+    let mut n = 0;
+    for dent in std::fs::read_dir(&*TRACES_DIR)? {
+        let path = dent?.path();
+        let Some(hash) = parse_trace_path(&path) else {
+            continue;
+        };
+        let (job, trace) = match TraceFile::read(&path) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("{e:#}");
+                continue;
+            }
+        };
+        record_in(&tmp, &job, hash, &trace)?;
+        n += 1;
+    }
+    // Swap the new index into place.  A concurrent reader may briefly find no
+    // index, in which case it'll rebuild one itself.
+    let live = redux_dir().join("index");
+    let old = redux_dir().join(format!(".index.{}.old", Uuid::new_v4()));
+    let _ = std::fs::rename(&live, &old); // Might be missing
+    std::fs::rename(&tmp, &live).with_context(|| format!("Replacing {}", live.display()))?;
+    let _ = std::fs::remove_dir_all(&old);
+    info!("Indexed {n} traces");
+    Ok(())
+}
+
+fn append(path: &Path, line: &str) -> anyhow::Result<()> {
+    let open = || File::options().create(true).append(true).open(path);
+    let mut f = match open() {
+        // Eg. the index was only partially rebuilt
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            open()
+        }
+        x => x,
+    }
+    .with_context(|| format!("Opening {}", path.display()))?;
+    // Lines are much shorter than PIPE_BUF, so as long as each one is written
+    // in one go, concurrent appends won't interleave
+    f.write_all(format!("{line}\n").as_bytes())?;
+    Ok(())
+}
+
+/// Returns the lines of an index file, skipping entries whose trace no longer
+/// exists.  Malformed lines are skipped too (with a warning), so that one bad
+/// entry doesn't make the whole file unusable.
+fn read(path: &Path) -> anyhow::Result<Vec<String>> {
+    let txt = match std::fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).with_context(|| format!("Reading {}", path.display())),
+    };
+    let mut lines = vec![];
+    for line in txt.lines() {
+        let Ok(hash) = line.split(' ').next().unwrap().parse::<Hash>() else {
+            warn!("{}: Skipping bad line: {line:?}", path.display());
+            continue;
+        };
+        if !trace_path(hash).exists() {
+            debug!("{}: Skipping stale entry {hash}", path.display());
+            continue;
+        }
+        lines.push(line.to_owned());
+    }
+    Ok(lines)
+}
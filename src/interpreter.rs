@@ -0,0 +1,129 @@
+//! How dofiles are run.  Like other redo implementations, a dofile which starts
+//! with a shebang is run by the interpreter it names.  Otherwise, executable
+//! dofiles are run directly, and anything else is run by `/bin/sh -e`.
+//! Dofiles don't need to be executable.
+//!
+//! The interpreter is recorded in the trace, with its path resolved (`/bin/sh`
+//! is usually a symlink to dash or bash), so that switching to a different one
+//! invalidates the outputs.
+
+use std::{
+    fmt,
+    io::Read,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tracing::warn;
+
+/// If set, shell dofiles are run with `-x`
+pub const ENV_VAR_XTRACE: &str = "REDUX_XTRACE";
+
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Interpreter {
+    /// The dofile is executable, but has no shebang (eg. it's a compiled
+    /// program), so it's run directly
+    Direct,
+    Program {
+        /// As written in the shebang
+        program: PathBuf,
+        /// Like the kernel, everything after the program name is passed as a
+        /// single argument
+        arg: Option<String>,
+    },
+}
+
+/// Shows the resolved path of the program, which is what goes in the trace
+impl fmt::Display for Interpreter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interpreter::Direct => write!(f, "(direct)"),
+            Interpreter::Program { program, arg } => {
+                let resolved = program.canonicalize();
+                write!(f, "{}", resolved.as_deref().unwrap_or(program).display())?;
+                if let Some(x) = arg {
+                    write!(f, " {x}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Interpreter {
+    /// The interpreter named by the dofile's shebang.  If it doesn't have one,
+    /// then it's run directly if it's executable, and by `/bin/sh -e` if not.
+    pub fn for_dofile(dofile: &Path) -> anyhow::Result<Interpreter> {
+        let mut file = std::fs::File::open(dofile)?;
+        let mut head = vec![];
+        (&mut file).take(256).read_to_end(&mut head)?;
+        let is_executable = file.metadata()?.permissions().mode() & 0o111 != 0;
+        Ok(Interpreter::for_contents(&head, is_executable))
+    }
+
+    /// Like `for_dofile()`, but given the start of the dofile's contents
+    pub fn for_contents(head: &[u8], is_executable: bool) -> Interpreter {
+        let shebang = head
+            .strip_prefix(b"#!")
+            .and_then(|x| x.split(|&c| c == b'\n').next())
+            .and_then(|x| std::str::from_utf8(x).ok())
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty());
+        if let Some(shebang) = shebang {
+            let (program, arg) = match shebang.split_once(char::is_whitespace) {
+                Some((x, y)) => (x, Some(y.trim_start().to_owned())),
+                None => (shebang, None),
+            };
+            return Interpreter::Program {
+                program: program.into(),
+                arg,
+            };
+        }
+        if is_executable {
+            Interpreter::Direct
+        } else {
+            Interpreter::Program {
+                program: DEFAULT_SHELL.into(),
+                arg: Some("-e".to_owned()),
+            }
+        }
+    }
+
+    fn is_shell(&self) -> bool {
+        let Interpreter::Program { program, arg } = self else {
+            return false;
+        };
+        let mut name = program.file_name().and_then(|x| x.to_str());
+        // `#!/usr/bin/env bash`: the shell is env's first argument which isn't
+        // an option (eg. `-S`) or a variable assignment
+        if name == Some("env") {
+            name = arg
+                .iter()
+                .flat_map(|x| x.split_whitespace())
+                .find(|x| !x.starts_with('-') && !x.contains('='))
+                .and_then(|x| x.rsplit('/').next());
+        }
+        matches!(name, Some("sh" | "bash" | "dash" | "ksh" | "zsh"))
+    }
+
+    /// A command which runs `dofile`.  The caller should add the dofile's
+    /// arguments.  If `xtrace` is set, shells are asked to print the commands
+    /// they run.
+    pub fn command(&self, dofile: &Path, xtrace: bool) -> Command {
+        if xtrace && !self.is_shell() {
+            warn!("{}: Not run by a shell; ignoring -x", dofile.display());
+        }
+        let Interpreter::Program { program, arg } = self else {
+            return Command::new(dofile);
+        };
+        let mut cmd = Command::new(program);
+        cmd.args(arg);
+        if xtrace && self.is_shell() {
+            cmd.arg("-x");
+        }
+        cmd.arg(dofile);
+        cmd
+    }
+}
@@ -1,21 +1,52 @@
 mod artifacts;
+mod bundle;
+mod config;
 mod depgraph;
 mod filestamp;
+mod fsck;
+mod gc;
+mod git_index;
+mod glob;
+mod hash_cache;
+mod index;
+mod interpreter;
 mod local_path;
+mod migrate;
+mod remote;
+mod rev;
 mod ruleset;
+mod signing;
+mod stats;
 mod trace;
+mod tree;
 
 pub use crate::{
     artifacts::Artifacts,
+    bundle::{export_cache, import_cache, BundleStats},
     depgraph::{DepGraph, TRACES_DIR},
-    filestamp::FileStamp,
+    filestamp::{FileKind, FileStamp},
+    fsck::{fsck, FsckStats, QUARANTINE_DIR},
+    gc::{gc, GcStats},
+    git_index::blob_line,
+    glob::{anchor_pattern, dir_pattern, GlobStamp},
+    interpreter::ENV_VAR_XTRACE,
     local_path::LocalPath,
+    migrate::{ensure_db_format, DB_FORMAT_VERSION},
+    rev::build_at_rev,
     ruleset::RuleSet,
+    signing::generate_key,
+    stats::{stats, ArtifactSize, BuildStats, Counters, Stats, StoreStats},
     trace::{EnvVar, TraceFile, TraceFileLine},
 };
 
+use crate::interpreter::Interpreter;
+use crate::remote::Remote;
+use crate::signing::TrustList;
+use crate::stats::Outcome;
 use crate::trace::{JobSpec, Trace};
 use anyhow::{anyhow, bail, ensure, Context};
+use rustix::fs::{flock, FlockOperation};
+use std::fs::File;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::LazyLock;
@@ -28,7 +59,8 @@ pub static REPO: LazyLock<gix::ThreadSafeRepository> =
 
 pub fn redux_dir() -> &'static Path {
     static REDUX_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
-        let redux_dir = REPO.git_dir().join("redux");
+        // Shared between all of the repo's worktrees
+        let redux_dir = REPO.to_thread_local().common_dir().join("redux");
         std::fs::create_dir_all(&redux_dir).unwrap();
         debug!("redux dir = {}", redux_dir.display());
         redux_dir.canonicalize().unwrap()
@@ -36,11 +68,20 @@ pub fn redux_dir() -> &'static Path {
     &REDUX_DIR
 }
 
+/// Contains one lockfile per in-progress build.  See `BuildId`.
+pub static BUILDS_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
+    let path = redux_dir().join("builds");
+    std::fs::create_dir_all(&path).unwrap();
+    path
+});
+
 /// A tracefile which was created by this process, and which should be moved or
 /// deleted before this process exits.
 struct JobTmpFiles {
     trace: TraceFile,
     out: PathBuf,
+    /// Where the job's stdout goes, if it's being captured
+    stdout: PathBuf,
     committed: bool,
 }
 impl JobTmpFiles {
@@ -48,16 +89,16 @@ impl JobTmpFiles {
     fn create(job: &JobSpec) -> anyhow::Result<Option<JobTmpFiles>> {
         match TraceFile::create(job.clone())? {
             Some(trace) => {
-                let outfile = {
-                    let filename = job.target.file_name();
-                    let target = job.abs_target();
-                    target.with_file_name(format!(".redux_{}.tmp", filename))
-                };
+                let filename = job.target.file_name();
+                let target = job.abs_target();
+                let outfile = target.with_file_name(format!(".redux_{}.tmp", filename));
+                let stdout = target.with_file_name(format!(".redux_{}.tmp.stdout", filename));
                 debug!(path = %trace.path.display(), "Prepared tracefile");
                 debug!(path = %outfile.display(), "Prepared outfile");
                 Ok(Some(JobTmpFiles {
                     trace,
                     out: outfile,
+                    stdout,
                     committed: false,
                 }))
             }
@@ -65,26 +106,84 @@ impl JobTmpFiles {
         }
     }
 
+    /// In stdout-capture mode: use the captured stdout as the output, unless
+    /// the job wrote to `$3`
+    fn adopt_stdout(&self) -> anyhow::Result<()> {
+        let captured = std::fs::metadata(&self.stdout)
+            .with_context(|| format!("Reading {}", self.stdout.display()))?;
+        if self.out.symlink_metadata().is_err() {
+            std::fs::rename(&self.stdout, &self.out)?;
+            return Ok(());
+        }
+        std::fs::remove_file(&self.stdout)?;
+        ensure!(
+            captured.len() == 0,
+            "Job wrote to both $3 and stdout (only one is allowed)"
+        );
+        Ok(())
+    }
+
     fn commit(mut self) -> anyhow::Result<Trace> {
-        ensure!(self.out.exists(), "Job produced no output");
+        ensure!(
+            self.out.symlink_metadata().is_ok(),
+            "Job produced no output"
+        );
 
         // Move the outfile _before_ moving the tracefile
         let job = &self.trace.job;
-        std::fs::rename(&self.out, job.abs_target())?;
-        let stamp = FileStamp::new(job.target.clone())?;
-        Artifacts::new()?.insert(&stamp)?;
+        replace_path(&self.out, &job.abs_target())?;
+        let mut outputs = vec![FileStamp::new(job.target.clone())?];
+        let (_, partial_trace) = TraceFile::read(&self.trace.path)?;
+        for path in partial_trace.declared_outputs {
+            if outputs.iter().any(|x| x.path == path) {
+                continue;
+            }
+            let stamp = FileStamp::new(path.clone())
+                .with_context(|| format!("{path}: Declared as an output, but not produced"))?;
+            outputs.push(stamp);
+        }
+        let mut artifacts = Artifacts::new()?;
+        for x in &outputs {
+            artifacts.insert(x)?;
+        }
+
+        // If a previous run produced the same target, then anything which
+        // depends on it can be cut off
+        let cut_off = index::traces_producing(outputs[0].hash)?
+            .iter()
+            .any(|(x, _)| x == job);
 
-        // Append one more line to the tracefile
-        self.trace.finish(stamp)?;
+        // Append the outputs to the tracefile
+        self.trace.finish(outputs)?;
 
         // Store the trace
         let tracefile_hash = FileStamp::new(self.trace.path.as_path().into())?.hash;
-        let new_tracefile = TRACES_DIR.join(format!("{tracefile_hash}.trace"));
+        let new_tracefile = index::trace_path(tracefile_hash);
         std::fs::rename(&self.trace.path, &new_tracefile)?;
         info!("Tracefile moved to {}", new_tracefile.display());
+        // The tracefile isn't ours to clean up any more
+        self.committed = true;
         let (_, trace) = TraceFile::read(&new_tracefile)?;
+        // The index can always be rebuilt from the trace store, so this isn't
+        // worth failing the job over
+        if let Err(e) = index::record(job, tracefile_hash, &trace) {
+            warn!("{}: Indexing {tracefile_hash}: {e:#}", job.target);
+        }
+        stats::record(
+            if cut_off {
+                Outcome::CutOff
+            } else {
+                Outcome::Ran
+            },
+            job,
+        );
+        for remote in Remote::from_config() {
+            // Remote caches are just caches: don't fail the build over them
+            if let Err(e) = remote.upload(job, tracefile_hash, &trace) {
+                warn!("{}: Uploading to {}: {e:#}", job.target, remote.name());
+            }
+        }
 
-        self.committed = true;
         Ok(trace)
     }
 }
@@ -98,7 +197,8 @@ impl Drop for JobTmpFiles {
                 "Cleaning up",
             );
             // Remove the outfile _before_ removing the tracefile
-            let _ = std::fs::remove_file(&self.out); // Might be missing
+            let _ = remove_path(&self.out); // Might be missing
+            let _ = std::fs::remove_file(&self.stdout); // Might not be capturing
             if let Err(e) = std::fs::remove_file(&self.trace.path) {
                 error!("{}: Failed to clean up: {e}", self.trace.path.display());
             }
@@ -106,19 +206,17 @@ impl Drop for JobTmpFiles {
     }
 }
 
-pub fn build(target: &LocalPath, force: bool) -> anyhow::Result<()> {
-    let rules = RuleSet::scan_for_do_files()?;
-    let job = rules
-        .job_for(target.clone())
-        .ok_or_else(|| anyhow!("{}: No rule matching this path", target))?;
+pub fn build(target: &LocalPath, force: bool, xtrace: bool) -> anyhow::Result<()> {
+    let job = job_for_target(target)?;
     debug!("Found rule {}", job.rule);
     let tmp_files = loop {
         if !force {
             // Try to re-use a prior build, if there is one
-            let restored = try_restore(&rules, &job)?;
+            let restored = try_restore(&job)?;
             if restored {
                 // The target file has been restored from the artifact store,
                 // and we're done!
+                stats::record(Outcome::Restored, &job);
                 return Ok(());
             }
         }
@@ -132,14 +230,59 @@ pub fn build(target: &LocalPath, force: bool) -> anyhow::Result<()> {
             }
         }
     };
-    actually_run(job, tmp_files, force)?;
+    actually_run(job.clone(), tmp_files, force, xtrace)?;
+    ensure!(
+        target.exists(),
+        "{target}: {job} didn't produce it this time"
+    );
     Ok(())
 }
 
-pub fn try_restore(rules: &RuleSet, job: &JobSpec) -> anyhow::Result<bool> {
+/// The job which builds `target`: either the one given by its dofile, or the
+/// one which produces it as a side output
+pub fn job_for_target(target: &LocalPath) -> anyhow::Result<JobSpec> {
+    match RuleSet::lookup(target.clone()) {
+        Some(job) => Ok(job),
+        None => {
+            side_output_of(target)?.ok_or_else(|| anyhow!("{}: No rule matching this path", target))
+        }
+    }
+}
+
+/// A path with no rule of its own may have been declared as a side output by
+/// some other job.  If so, returns that job (the most recent, if there are
+/// several).
+fn side_output_of(target: &LocalPath) -> anyhow::Result<Option<JobSpec>> {
+    let trust = TrustList::from_config();
+    let job = index::traces_producing_path(target)?
+        .into_iter()
+        .rev()
+        .filter(|(job, _)| job.target != *target && RuleSet::is_job_current(job))
+        .find(|(_, hash)| {
+            TraceFile::read_trusted(&index::trace_path(*hash), &trust).is_ok_and(|x| x.is_some())
+        })
+        .map(|(job, _)| job);
+    if let Some(job) = &job {
+        debug!("{target}: Side output of {job}");
+    }
+    Ok(job)
+}
+
+pub fn try_restore(job: &JobSpec) -> anyhow::Result<bool> {
+    let remotes = Remote::from_config();
     // Need to reload the dep graph each time
-    let dep_graph = DepGraph::load(rules)?;
-    let Some(tree) = dep_graph.valid_trace_for(job) else {
+    let mut tree = DepGraph::load_for(job)?.valid_trace_for(job);
+    for remote in &remotes {
+        if tree.is_some() {
+            break;
+        }
+        match remote.fetch_traces(job) {
+            Ok(0) => (),
+            Ok(_) => tree = DepGraph::load_for(job)?.valid_trace_for(job),
+            Err(e) => warn!("{}: Querying {}: {e:#}", job.target, remote.name()),
+        }
+    }
+    let Some(tree) = tree else {
         return Ok(false);
     };
     info!(
@@ -147,22 +290,99 @@ pub fn try_restore(rules: &RuleSet, job: &JobSpec) -> anyhow::Result<bool> {
         job.target
     );
     info!("{tree}");
-    let x = tree.outputs.iter().find(|x| x.path == job.target).unwrap();
-    Artifacts::new()?.restore(x)?;
+    // Restore the side outputs too, since the job won't be run
+    let mut artifacts = Artifacts::new()?;
+    for x in &tree.outputs {
+        if artifacts.contains_stamp(x) {
+            continue;
+        }
+        let fetched = remotes.iter().any(|remote| {
+            remote
+                .fetch_artifacts(&mut artifacts, x)
+                .inspect_err(|e| warn!("{e:#}"))
+                .is_ok()
+        });
+        if !fetched {
+            info!("{}: Output has been evicted from the store", x.path);
+            return Ok(false);
+        }
+    }
+    for x in &tree.outputs {
+        artifacts.restore(x)?;
+    }
     Ok(true)
 }
 
+/// Move `from` to `to`, atomically replacing whatever is already there - even
+/// if it's a directory.
+fn replace_path(from: &Path, to: &Path) -> anyhow::Result<()> {
+    use rustix::fs::{renameat_with, RenameFlags, CWD};
+    match renameat_with(CWD, from, CWD, to, RenameFlags::EXCHANGE) {
+        // `from` now holds the old contents of `to`
+        Ok(()) => remove_path(from),
+        // Nothing at `to` yet
+        Err(rustix::io::Errno::NOENT) => Ok(std::fs::rename(from, to)?),
+        // The filesystem doesn't support RENAME_EXCHANGE.  A plain rename
+        // can't replace a dir, so remove it first (non-atomically).
+        Err(e) => {
+            debug!(
+                "{}: Can't exchange ({e}); falling back to rename",
+                to.display()
+            );
+            if to.is_dir() {
+                std::fs::remove_dir_all(to)?;
+            }
+            Ok(std::fs::rename(from, to)?)
+        }
+    }
+    .with_context(|| format!("Moving {} to {}", from.display(), to.display()))
+}
+
+/// Remove a file or a directory tree
+fn remove_path(path: &Path) -> anyhow::Result<()> {
+    if std::fs::symlink_metadata(path)?.is_dir() {
+        std::fs::remove_dir_all(path)?;
+    } else {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
 #[derive(Debug, Hash, PartialEq, Eq, Clone, Copy, Default, PartialOrd, Ord)]
 pub struct BuildId(pub Uuid);
 
 impl BuildId {
-    fn new() -> Self {
+    /// Creates a new build ID and registers it in `BUILDS_DIR`.  The lockfile
+    /// stays locked until this process exits, so other processes can tell
+    /// whether the build is still running.
+    fn new() -> anyhow::Result<Self> {
+        let id = BuildId(Uuid::new_v4());
+        let path = id.lockfile();
+        let f = File::create_new(&path).with_context(|| format!("Creating {}", path.display()))?;
+        flock(&f, FlockOperation::NonBlockingLockExclusive)
+            .with_context(|| format!("Flocking {}", path.display()))?;
+        // Keep the file open (and hence locked) for the rest of our life
+        std::mem::forget(f);
         debug!("Created new build ID");
-        BuildId(Uuid::new_v4())
+        Ok(id)
     }
 
+    /// The build this process belongs to.  A top-level invocation of redux
+    /// creates a new one (just once, even if it builds several targets).
     pub fn current_or_new() -> anyhow::Result<BuildId> {
-        Ok(Self::current()?.unwrap_or_else(Self::new))
+        static OURS: std::sync::Mutex<Option<BuildId>> = std::sync::Mutex::new(None);
+        if let Some(x) = Self::current()? {
+            return Ok(x);
+        }
+        let mut ours = OURS.lock().unwrap();
+        match *ours {
+            Some(x) => Ok(x),
+            None => Ok(*ours.insert(Self::new()?)),
+        }
+    }
+
+    pub fn lockfile(self) -> PathBuf {
+        BUILDS_DIR.join(self.0.to_string())
     }
 
     pub fn is_current(self) -> bool {
@@ -172,6 +392,18 @@ impl BuildId {
         }
     }
 
+    /// A build is finished once its lockfile is gone or no longer locked.
+    /// Volatile traces from a finished build can never be valid again.
+    pub fn is_finished(self) -> bool {
+        if self.is_current() {
+            return false;
+        }
+        let Ok(f) = File::open(self.lockfile()) else {
+            return true;
+        };
+        flock(&f, FlockOperation::NonBlockingLockShared).is_ok()
+    }
+
     fn current() -> anyhow::Result<Option<BuildId>> {
         match std::env::var(ENV_VAR_BUILD_ID) {
             Ok(x) => Ok(Some(BuildId(x.parse()?))),
@@ -185,12 +417,31 @@ pub const ENV_VAR_TRACEFILE: &str = "REDUX_TRACEFILE";
 pub const ENV_VAR_BUILD_ID: &str = "REDUX_BUILD_ID";
 pub const ENV_VAR_FORCE: &str = "REDUX_FORCE";
 
-fn actually_run(job: JobSpec, tmp_files: JobTmpFiles, force: bool) -> anyhow::Result<Trace> {
+fn actually_run(
+    job: JobSpec,
+    tmp_files: JobTmpFiles,
+    force: bool,
+    xtrace: bool,
+) -> anyhow::Result<Trace> {
     info!("Running rule to build file");
-    let cmd = job.rule.to_abs();
-    let job_dir = cmd.parent().unwrap();
+    let dofile = job.rule.to_abs();
+    let job_dir = dofile.parent().unwrap();
     let build_id = BuildId::current_or_new()?;
-    let mut child = std::process::Command::new(&cmd)
+    let interpreter = Interpreter::for_dofile(&dofile)
+        .with_context(|| format!("Reading {}", dofile.display()))?;
+    TraceFile::append(
+        Some(&tmp_files.trace),
+        TraceFileLine::Interpreter(interpreter.to_string()),
+    )?;
+    let capture_stdout = config::capture_stdout(&job.rule);
+    let mut cmd = interpreter.command(&dofile, xtrace);
+    if capture_stdout {
+        TraceFile::append(Some(&tmp_files.trace), TraceFileLine::CaptureStdout)?;
+        let f = File::create(&tmp_files.stdout)
+            .with_context(|| format!("Creating {}", tmp_files.stdout.display()))?;
+        cmd.stdout(f);
+    }
+    let mut child = cmd
         .current_dir(job_dir)
         // the name of the target file
         .arg(job.target_relative_to_rule())
@@ -203,20 +454,27 @@ fn actually_run(job: JobSpec, tmp_files: JobTmpFiles, force: bool) -> anyhow::Re
         .env(ENV_VAR_TRACEFILE, &tmp_files.trace.path)
         .env(ENV_VAR_BUILD_ID, build_id.0.to_string())
         .envs(force.then_some((ENV_VAR_FORCE, "1")))
+        .envs(xtrace.then_some((ENV_VAR_XTRACE, "1")))
         .spawn()
-        .context(format!(
-            "Spawn cmd {} in {}",
-            cmd.to_str().unwrap(),
-            job_dir.display(),
-        ))?;
+        .with_context(|| {
+            format!(
+                "Running {} with {interpreter} in {}",
+                dofile.display(),
+                job_dir.display(),
+            )
+        })?;
     let exit_status = child.wait().context("Wait for child")?;
     debug!("Child finished: {exit_status}");
     if exit_status.success() {
+        if capture_stdout {
+            tmp_files.adopt_stdout()?;
+        }
         let trace = tmp_files.commit()?;
         info!("Finished build");
         Ok(trace)
     } else if exit_status.code() == Some(102) {
         info!("Looks like the job bailed out early");
+        stats::record(Outcome::BailedOut, &job);
         assert!(job.target.exists());
         let (_, partial_trace) = TraceFile::read(&tmp_files.trace.path)?;
         Ok(partial_trace)
@@ -236,7 +494,12 @@ pub fn is_source(path: &LocalPath) -> anyhow::Result<bool> {
         debug!("{path}: Doesn't exist => generated");
         return Ok(false);
     };
-    if DepGraph::load_all()?.outputs().any(|x| x == &stamp) {
+    let trust = TrustList::from_config();
+    let generated = index::traces_producing(stamp.hash)?
+        .into_iter()
+        .map(|(_, hash)| TraceFile::read_trusted(&index::trace_path(hash), &trust))
+        .any(|x| x.is_ok_and(|x| x.is_some_and(|(_, trace)| trace.outputs.contains(&stamp))));
+    if generated {
         debug!("{path}: We generated it => generated");
         Ok(false)
     } else {
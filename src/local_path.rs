@@ -49,8 +49,9 @@ impl LocalPath {
         LocalPath(self.0.join(component))
     }
 
+    /// Unlike `Path::exists()`, this is true for dangling symlinks
     pub fn exists(&self) -> bool {
-        self.to_abs().exists()
+        self.to_abs().symlink_metadata().is_ok()
     }
 }
 
@@ -69,10 +70,13 @@ impl FromStr for LocalPath {
 impl From<&Path> for LocalPath {
     fn from(path: &Path) -> Self {
         let abs = std::env::current_dir().unwrap().join(path);
-        let canonical = match abs.canonicalize() {
-            Ok(x) => x,
-            Err(_) => abs,
+        // Resolve symlinks in the parent dirs, but not in the last component:
+        // if the path is itself a symlink, that's what we want to refer to
+        let canonical = match (abs.parent(), abs.file_name()) {
+            (Some(parent), Some(name)) => parent.canonicalize().map(|x| x.join(name)),
+            _ => abs.canonicalize(),
         };
+        let canonical = canonical.unwrap_or(abs);
         let local = pathdiff::diff_paths(canonical, project_base()).unwrap();
         LocalPath(local)
     }
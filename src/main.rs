@@ -1,8 +1,9 @@
 use anyhow::{anyhow, bail, Context};
 use bpaf::{Bpaf, Parser};
 use redux::{
-    is_source, try_restore, Artifacts, BuildId, DepGraph, EnvVar, FileStamp, LocalPath, RuleSet,
-    TraceFile, TraceFileLine, ENV_VAR_FORCE, TRACES_DIR,
+    is_source, try_restore, Artifacts, BuildId, DepGraph, EnvVar, FileKind, FileStamp, GlobStamp,
+    LocalPath, RuleSet, TraceFile, TraceFileLine, ENV_VAR_FORCE, ENV_VAR_XTRACE, QUARANTINE_DIR,
+    TRACES_DIR,
 };
 use std::collections::{BTreeSet, HashMap};
 use std::fs::File;
@@ -29,7 +30,28 @@ enum Command {
     },
     /// Remove items from the database which are no longer useful
     #[bpaf(command("--gc"))]
-    GC,
+    GC {
+        /// Report what would be removed, but don't remove anything
+        dry_run: bool,
+    },
+    /// Show how well the cache has been working, and what's in it
+    #[bpaf(command("--stats"))]
+    Stats {
+        /// Print the stats as JSON
+        json: bool,
+        /// How many recent builds to show
+        #[bpaf(argument("NUM"), fallback(10), display_fallback)]
+        builds: usize,
+        /// How many of the largest artifacts to show
+        #[bpaf(argument("NUM"), fallback(10), display_fallback)]
+        largest: usize,
+    },
+    /// Check the integrity of the database
+    #[bpaf(command("--fsck"))]
+    Fsck {
+        /// Move corrupt artifacts and traces out of the database
+        quarantine: bool,
+    },
     /// Watch an in-progress build
     #[bpaf(command("--watch"))]
     Watch {
@@ -74,6 +96,38 @@ enum Command {
         /// Remove redux's build database as well
         database: bool,
     },
+    /// Write the traces and artifacts needed to restore the given files (or
+    /// everything valid in the current tree) to a bundle
+    #[bpaf(command("--export-cache"))]
+    ExportCache {
+        #[bpaf(positional("FILE"))]
+        file: PathBuf,
+        #[bpaf(positional("PATH"))]
+        targets: Vec<PathBuf>,
+    },
+    /// Create a key for signing traces, and print its public half
+    #[bpaf(command("--gen-signing-key"))]
+    GenSigningKey {
+        #[bpaf(positional("FILE"))]
+        file: PathBuf,
+    },
+    /// Build a file as it would be at the given commit, without checking it out
+    #[bpaf(command("--rev"))]
+    Rev {
+        /// Where to write the result
+        #[bpaf(short, long, argument("PATH"))]
+        output: PathBuf,
+        #[bpaf(positional("COMMIT"))]
+        rev: String,
+        #[bpaf(positional("PATH"))]
+        target: PathBuf,
+    },
+    /// Merge a bundle written by --export-cache into the build cache
+    #[bpaf(command("--import-cache"))]
+    ImportCache {
+        #[bpaf(positional("FILE"))]
+        file: PathBuf,
+    },
 }
 
 #[derive(Bpaf, Clone)]
@@ -89,9 +143,25 @@ struct BuildOpts {
     /// Read a GCC-style depfile and mark the contents as dependencies
     #[bpaf(long, argument("PATH"))]
     depfile: Option<PathBuf>,
+    /// Depend on the set of files matching this pattern (relative to the
+    /// current dir), as well as on the files themselves
+    #[bpaf(long, argument("PATTERN"))]
+    glob: Vec<String>,
+    /// Depend on everything under this directory, including which files exist
+    #[bpaf(long, argument("PATH"))]
+    dir: Vec<PathBuf>,
+    /// Depend on this file not existing: the job will be re-run if it's created
+    #[bpaf(long, argument("PATH"))]
+    ifcreate: Vec<PathBuf>,
+    /// Declare that the current job produces this file too, alongside $3
+    #[bpaf(long, argument("PATH"))]
+    also_produces: Vec<PathBuf>,
     /// Don't re-use any files from the build cache (recursive)
     #[bpaf(short, long)]
     force: bool,
+    /// Print the commands run by shell dofiles, like `sh -x` (recursive)
+    #[bpaf(short('x'), long)]
+    xtrace: bool,
     /// Limit parallelism to this many jobs (uses all cores by default)
     #[bpaf(
         short,
@@ -134,9 +204,47 @@ fn main() -> anyhow::Result<()> {
         .with(EnvFilter::from_default_env())
         .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
         .init();
+    redux::ensure_db_format()?;
     match opts.command {
-        Command::GC => {
-            todo!()
+        Command::GC { dry_run } => {
+            let stats = redux::gc(dry_run)?;
+            println!(
+                "{} {} trace(s) and {} artifact(s), freeing {} bytes",
+                if dry_run { "Would remove" } else { "Removed" },
+                stats.traces,
+                stats.artifacts,
+                stats.bytes,
+            );
+        }
+        Command::Fsck { quarantine } => {
+            let stats = redux::fsck(quarantine)?;
+            println!(
+                "Checked {} artifact(s) and {} trace(s): {} corrupt, {} with missing outputs, \
+                 {} untrusted",
+                stats.artifacts,
+                stats.traces,
+                stats.corrupt,
+                stats.missing_outputs,
+                stats.untrusted,
+            );
+            if stats.corrupt > 0 {
+                if quarantine {
+                    println!("Corrupt entries moved to {}", QUARANTINE_DIR.display());
+                }
+                std::process::exit(1);
+            }
+        }
+        Command::Stats {
+            json,
+            builds,
+            largest,
+        } => {
+            let stats = redux::stats(builds, largest)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            } else {
+                print_stats(&stats);
+            }
         }
         Command::Watch { target } => {
             let fname = target.file_name().unwrap().to_str().unwrap();
@@ -154,6 +262,35 @@ fn main() -> anyhow::Result<()> {
         Command::Depgraph { target, all } => dep_graph(target.as_deref(), all)?,
         Command::Sources { all } => sources(all)?,
         Command::Outputs { all } => outputs(all)?,
+        Command::ExportCache { file, targets } => {
+            let targets: Vec<LocalPath> = targets.into_iter().map(Into::into).collect();
+            let stats = redux::export_cache(&file, &targets)?;
+            println!(
+                "Exported {} trace(s) and {} artifact(s) to {}",
+                stats.traces,
+                stats.artifacts,
+                file.display(),
+            );
+        }
+        Command::GenSigningKey { file } => {
+            let key = redux::generate_key(&file)?;
+            println!("Wrote a new signing key to {}", file.display());
+            println!(
+                "To sign traces with it:\n    git config redux.signingKey {}",
+                file.display()
+            );
+            println!(
+                "To trust traces signed with it:\n    git config --add redux.trustedKey {}",
+                hex::encode(key.as_bytes())
+            );
+        }
+        Command::ImportCache { file } => {
+            let stats = redux::import_cache(&file)?;
+            println!(
+                "Imported {} new trace(s) and {} new artifact(s)",
+                stats.traces, stats.artifacts,
+            );
+        }
         Command::Clean { database } => {
             let dep_graph = DepGraph::load_all()?;
             let outputs: BTreeSet<&LocalPath> = dep_graph.outputs().map(|x| &x.path).collect();
@@ -162,12 +299,16 @@ fn main() -> anyhow::Result<()> {
                 if let Ok(stamp) = FileStamp::new(s.clone()) {
                     artifacts.insert(&stamp)?;
                     let p = s.to_abs();
-                    std::fs::remove_file(&p)
-                        .with_context(|| format!("Removing {}", p.display()))?;
+                    if stamp.kind == FileKind::Dir {
+                        std::fs::remove_dir_all(&p)
+                    } else {
+                        std::fs::remove_file(&p)
+                    }
+                    .with_context(|| format!("Removing {}", p.display()))?;
                     println!(
                         "{}: Removed (available at {})",
                         s,
-                        Artifacts::store_path(stamp.hash).display(),
+                        artifacts.store_path(stamp.hash).unwrap().display(),
                     );
                 }
             }
@@ -175,6 +316,11 @@ fn main() -> anyhow::Result<()> {
                 std::fs::remove_dir_all(&*TRACES_DIR)?;
             }
         }
+        Command::Rev {
+            output,
+            rev,
+            target,
+        } => redux::build_at_rev(&rev, &target.into(), &output)?,
         Command::Build { build_opts } => build(build_opts)?,
     }
     Ok(())
@@ -226,9 +372,22 @@ fn build(opts: BuildOpts) -> anyhow::Result<()> {
         stamp,
         jobs,
         force,
+        xtrace,
         depfile,
+        glob,
+        dir,
+        ifcreate,
+        also_produces,
     } = opts;
-    if targets.is_empty() && volatile.is_none() && env_var.is_empty() && !stamp && depfile.is_none()
+    if targets.is_empty()
+        && volatile.is_none()
+        && env_var.is_empty()
+        && !stamp
+        && depfile.is_none()
+        && glob.is_empty()
+        && dir.is_empty()
+        && ifcreate.is_empty()
+        && also_produces.is_empty()
     {
         bail!("No targets specified");
     }
@@ -240,11 +399,31 @@ fn build(opts: BuildOpts) -> anyhow::Result<()> {
         targets.extend(deps.into_values().flatten());
     }
 
-    for target in &targets {
-        if target.is_dir() {
-            bail!("{}: Dir already exists at this path", target.display());
+    let cwd = LocalPath::from(Path::new("."));
+    let patterns = glob
+        .iter()
+        .map(|x| redux::anchor_pattern(&cwd, x))
+        .chain(dir.into_iter().map(|x| redux::dir_pattern(&x.into())))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // The job's own outputs mustn't count as matches, or the job would end up
+    // depending on itself
+    let mut own_outputs = also_produces
+        .iter()
+        .map(|x| LocalPath::from(x.as_path()))
+        .collect::<Vec<_>>();
+    if !patterns.is_empty() {
+        if let Some(tracefile) = TraceFile::current()? {
+            let (job, partial_trace) = TraceFile::read(&tracefile.path)?;
+            own_outputs.push(job.target);
+            own_outputs.extend(partial_trace.declared_outputs);
         }
     }
+    let mut globs = vec![];
+    for pattern in patterns {
+        let (stamp, paths) = GlobStamp::new(pattern, &own_outputs)?;
+        targets.extend(paths.iter().map(|x| x.to_abs()));
+        globs.push(stamp);
+    }
 
     // NOTE: Read the implementation of get_jobserver() - it may restart
     // the current process!
@@ -252,6 +431,7 @@ fn build(opts: BuildOpts) -> anyhow::Result<()> {
     let jobserver = needs_jobserver.then(|| get_jobserver(jobs)).transpose()?;
 
     let force = force || std::env::var(ENV_VAR_FORCE).is_ok();
+    let xtrace = xtrace || std::env::var(ENV_VAR_XTRACE).is_ok();
     let tracefile = TraceFile::current()?;
 
     if let Some(volatile) = volatile {
@@ -269,6 +449,17 @@ fn build(opts: BuildOpts) -> anyhow::Result<()> {
         TraceFile::append(tracefile.as_ref(), line)?;
     }
 
+    for x in globs {
+        TraceFile::append(tracefile.as_ref(), TraceFileLine::Glob(x))?;
+    }
+
+    for path in ifcreate {
+        if path.symlink_metadata().is_ok() {
+            bail!("--ifcreate: {} already exists", path.display());
+        }
+        TraceFile::append(tracefile.as_ref(), TraceFileLine::Absent(path.into()))?;
+    }
+
     for key in env_var {
         let val = std::env::var(&key)?;
         TraceFile::append(
@@ -277,6 +468,13 @@ fn build(opts: BuildOpts) -> anyhow::Result<()> {
         )?;
     }
 
+    if !also_produces.is_empty() && tracefile.is_none() {
+        bail!("--also-produces can only be used from within a dofile");
+    }
+    for path in also_produces {
+        TraceFile::append(tracefile.as_ref(), TraceFileLine::AlsoProduces(path.into()))?;
+    }
+
     if stamp {
         let mut hasher = blake3::Hasher::new();
         std::io::copy(&mut std::io::stdin(), &mut hasher)?;
@@ -298,24 +496,62 @@ fn build(opts: BuildOpts) -> anyhow::Result<()> {
             let target: LocalPath = target.into();
             let _g = info_span!("build", %target).entered();
             let is_source = is_source(&target)?;
+            // Only generated dirs are stamped as a whole
+            let is_dir = target.to_abs().symlink_metadata().is_ok_and(|x| x.is_dir());
+            if is_source && is_dir {
+                bail!("{target}: Dir already exists at this path; use --dir to depend on it");
+            }
             if !is_source {
-                redux::build(&target, force)?;
+                redux::build(&target, force, xtrace)?;
+            }
+            let stamp = FileStamp::new(target.clone())?;
+            let mut artifacts = Artifacts::new()?;
+            artifacts.insert(&stamp)?;
+            let mut lines = vec![];
+            // A symlink's stamp only covers the path it points to, but the job
+            // presumably cares about the contents too.  We only follow links
+            // to regular files inside the project, which are treated like any
+            // other target.
+            if let Some(pointee) = symlink_pointee(&stamp) {
+                let pointee_is_source = redux::is_source(&pointee)?;
+                if !pointee_is_source {
+                    redux::build(&pointee, force, xtrace)?;
+                }
+                if pointee
+                    .to_abs()
+                    .symlink_metadata()
+                    .is_ok_and(|x| x.is_file())
+                {
+                    let stamp = FileStamp::new(pointee)?;
+                    artifacts.insert(&stamp)?;
+                    if pointee_is_source {
+                        let blob = redux::blob_line(&stamp);
+                        lines.push(TraceFileLine::Source(stamp));
+                        lines.extend(blob);
+                    } else {
+                        lines.push(TraceFileLine::Generated(stamp));
+                    }
+                }
             }
-            let stamp = FileStamp::new(target)?;
-            Artifacts::new()?.insert(&stamp)?;
-            let line = if is_source {
-                TraceFileLine::Source(stamp)
+            if is_source {
+                let blob = redux::blob_line(&stamp);
+                lines.push(TraceFileLine::Source(stamp));
+                lines.extend(blob);
             } else {
-                TraceFileLine::Generated(stamp)
-            };
-            anyhow::Ok(line)
+                lines.push(TraceFileLine::Generated(stamp));
+            }
+            anyhow::Ok(lines)
         }));
         std::mem::drop(token);
     }
     let mut errored = false;
     for th in threads {
         match th.join().unwrap() {
-            Ok(line) => TraceFile::append(tracefile.as_ref(), line)?,
+            Ok(lines) => {
+                for line in lines {
+                    TraceFile::append(tracefile.as_ref(), line)?;
+                }
+            }
             Err(e) => {
                 error!("{e:?}");
                 errored = true;
@@ -325,10 +561,15 @@ fn build(opts: BuildOpts) -> anyhow::Result<()> {
     if errored {
         bail!("One of the build jobs failed");
     }
+    // Everything the build produced has been committed by now
+    if tracefile.is_none() {
+        if let Err(e) = Artifacts::new()?.enforce_configured_budget() {
+            error!("Enforcing the artifact store's budget: {e:#}");
+        }
+    }
     if !force {
         if let Some(TraceFile { job, .. }) = TraceFile::current()? {
-            let rules = RuleSet::scan_for_do_files()?;
-            let restored = try_restore(&rules, &job)?;
+            let restored = try_restore(&job)?;
             if restored {
                 info!("{job}: Looks like we can bail out at this point!");
                 std::process::exit(102);
@@ -338,6 +579,64 @@ fn build(opts: BuildOpts) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// The file a symlink points to, if it's an existing regular file inside the
+/// project
+fn symlink_pointee(link: &FileStamp) -> Option<LocalPath> {
+    if link.kind != FileKind::Symlink {
+        return None;
+    }
+    let abs = link.path.to_abs();
+    let dest = abs.parent()?.join(std::fs::read_link(&abs).ok()?);
+    let pointee = LocalPath::from(dest);
+    if pointee.as_path().starts_with("..") {
+        return None;
+    }
+    pointee
+        .to_abs()
+        .symlink_metadata()
+        .is_ok_and(|x| x.is_file())
+        .then_some(pointee)
+}
+
+fn print_stats(stats: &redux::Stats) {
+    fn summary(x: &redux::Counters) -> String {
+        let mut txt = format!(
+            "{} restored, {} run, {} bailed out, {} cut off",
+            x.restored, x.ran, x.bailed_out, x.cut_off,
+        );
+        if let Some(rate) = x.hit_rate() {
+            txt.push_str(&format!(" ({:.0}% hit rate)", rate * 100.0));
+        }
+        txt
+    }
+    println!("Recent builds:");
+    for x in &stats.builds {
+        println!("  {}  {}", x.started, summary(&x.counters));
+    }
+    println!("All builds: {}", summary(&stats.total));
+    let store = &stats.store;
+    println!(
+        "Traces: {} ({} bytes), of which {} volatile",
+        store.traces, store.trace_bytes, store.volatile_traces,
+    );
+    println!(
+        "Artifacts: {} ({} bytes)",
+        store.artifacts, store.artifact_bytes,
+    );
+    if !store.largest_artifacts.is_empty() {
+        println!("Largest artifacts:");
+    }
+    for x in &store.largest_artifacts {
+        let line = format!(
+            "  {:>12} bytes  {}  {}",
+            x.bytes,
+            &x.hash[..8],
+            x.paths.join(", ")
+        );
+        println!("{}", line.trim_end());
+    }
+}
+
 fn get_jobserver(jobs: usize) -> anyhow::Result<jobserver::Client> {
     if let Some(client) = unsafe { jobserver::Client::from_env() } {
         return Ok(client);
@@ -384,7 +683,7 @@ fn dep_graph(target: Option<&Path>, all: bool) -> anyhow::Result<()> {
     let mut dep_graph = DepGraph::load_all()?;
     let rules = RuleSet::scan_for_do_files()?;
     if !all {
-        dep_graph.drop_superseded(&rules);
+        dep_graph.drop_superseded();
         dep_graph.drop_out_of_date();
     }
     if let Some(target) = target {
@@ -397,7 +696,7 @@ fn dep_graph(target: Option<&Path>, all: bool) -> anyhow::Result<()> {
         println!("{tree}");
     } else {
         for (j, ts) in dep_graph.traces {
-            for t in ts {
+            for t in ts.values() {
                 println!("{}: {t}", j.fancy());
             }
         }
@@ -0,0 +1,193 @@
+//! The redux dir records the version of its format in `redux_dir/format`.  If
+//! it was written by an older redux, we upgrade it in place; if it was written
+//! by a newer redux, we refuse to touch it.
+
+use crate::{artifacts, index, redux_dir, REPO};
+use anyhow::{bail, Context};
+use rustix::fs::{flock, FlockOperation};
+use std::{fs::File, path::Path};
+use tracing::info;
+
+/// The format written by this version of redux
+pub const DB_FORMAT_VERSION: u32 = 7;
+
+type Migration = fn(&Path) -> anyhow::Result<()>;
+
+/// `MIGRATIONS[n]` upgrades a redux dir from format `n` to format `n + 1`
+const MIGRATIONS: [(&str, Migration); DB_FORMAT_VERSION as usize] = [
+    (
+        "shard the artifact store and rebuild the indexes",
+        migrate_0_to_1,
+    ),
+    ("stamps may refer to directories (`tree:`)", syntax_only),
+    (
+        "stamps record executables and symlinks (`exec:`, `link:`); traces which \
+         depend on them (including every dofile) will be rebuilt",
+        syntax_only,
+    ),
+    ("traces may depend on glob patterns (`glob`)", syntax_only),
+    (
+        "traces may depend on files not existing (`absent`)",
+        syntax_only,
+    ),
+    (
+        "traces record whether stdout was captured (`capture_stdout`)",
+        syntax_only,
+    ),
+    ("traces record the interpreter (`interpreter`)", syntax_only),
+];
+
+/// Make sure the redux dir is in the current format, upgrading it if
+/// necessary.  Call this before touching anything in the redux dir.
+pub fn ensure_db_format() -> anyhow::Result<()> {
+    let dir = redux_dir();
+    if read_version(dir)? != Some(DB_FORMAT_VERSION) {
+        // Make sure only one process migrates at a time
+        let Some(_lock) = lock(dir)? else {
+            bail!("{}: Disappeared", dir.display());
+        };
+        upgrade(dir)?;
+    }
+    adopt_worktree_dir()
+}
+
+/// `None` means the dir no longer exists
+fn lock(dir: &Path) -> anyhow::Result<Option<File>> {
+    let lock_path = dir.join("format.lock");
+    let lock = match File::create(&lock_path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Creating {}", lock_path.display())),
+    };
+    flock(&lock, FlockOperation::LockExclusive)
+        .with_context(|| format!("Flocking {}", lock_path.display()))?;
+    Ok(Some(lock))
+}
+
+/// The caller must hold the dir's lock
+fn upgrade(dir: &Path) -> anyhow::Result<()> {
+    // Someone else may have migrated it while we were waiting for the lock
+    let mut version = match read_version(dir)? {
+        Some(x) => x,
+        // Versioning was introduced in format 1.  If there's any data, it must
+        // be from before then.
+        None if is_fresh(dir)? => DB_FORMAT_VERSION,
+        None => 0,
+    };
+    if version > DB_FORMAT_VERSION {
+        bail!(
+            "{} uses database format {version}, but this version of redux only \
+             understands formats up to {DB_FORMAT_VERSION}.  Please upgrade redux \
+             (or delete the redux dir).",
+            dir.display(),
+        );
+    }
+    while version < DB_FORMAT_VERSION {
+        let (desc, migration) = MIGRATIONS[version as usize];
+        info!("Migrating the redux dir from format {version}: {desc}");
+        migration(dir)
+            .with_context(|| format!("Migrating {} from format {version}", dir.display()))?;
+        version += 1;
+        write_version(dir, version)?;
+    }
+    write_version(dir, version)?;
+    Ok(())
+}
+
+/// The redux dir used to live in the git dir, which for a linked worktree is
+/// `.git/worktrees/<name>`.  Now it's shared by all worktrees.  If this
+/// worktree still has a redux dir of its own, move its traces, artifacts and
+/// stats into the shared one, so they aren't orphaned.
+fn adopt_worktree_dir() -> anyhow::Result<()> {
+    let old = REPO.git_dir().join("redux");
+    if !old.exists() || old.canonicalize()? == redux_dir() {
+        return Ok(()); // Fast path
+    }
+    let Some(_lock) = lock(&old)? else {
+        return Ok(()); // Someone else beat us to it
+    };
+    // ...or they did while we were waiting for the lock.  (The lockfile was
+    // removed along with the dir, but we've still got it open.)
+    if !old.exists() {
+        return Ok(());
+    }
+    info!(
+        "Moving {} into the shared redux dir, {}",
+        old.display(),
+        redux_dir().display()
+    );
+    upgrade(&old)?;
+    for subdir in ["traces", "artifacts", "stats"] {
+        let from = old.join(subdir);
+        if !from.exists() {
+            continue;
+        }
+        for ent in walkdir::WalkDir::new(&from) {
+            let ent = ent?;
+            // Skip in-flight temporary files
+            let is_tmp = ent.file_name().to_str().is_none_or(|x| x.starts_with('.'));
+            if !ent.file_type().is_file() || is_tmp {
+                continue;
+            }
+            // Everything in here is content-addressed (or, for stats, named
+            // by build ID), so if the shared dir already has it then it's the
+            // same
+            let to = redux_dir()
+                .join(subdir)
+                .join(ent.path().strip_prefix(&from)?);
+            if to.exists() {
+                continue;
+            }
+            std::fs::create_dir_all(to.parent().unwrap())?;
+            std::fs::rename(ent.path(), &to)
+                .with_context(|| format!("Moving {} to {}", ent.path().display(), to.display()))?;
+        }
+    }
+    std::fs::remove_dir_all(&old).with_context(|| format!("Removing {}", old.display()))?;
+    index::rebuild()
+}
+
+fn read_version(dir: &Path) -> anyhow::Result<Option<u32>> {
+    let path = dir.join("format");
+    match std::fs::read_to_string(&path) {
+        Ok(x) => Ok(Some(x.trim().parse().with_context(|| {
+            format!("{}: Invalid format version", path.display())
+        })?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Reading {}", path.display())),
+    }
+}
+
+fn write_version(dir: &Path, version: u32) -> anyhow::Result<()> {
+    let tmp = dir.join("format.tmp");
+    std::fs::write(&tmp, format!("{version}\n"))?;
+    std::fs::rename(&tmp, dir.join("format"))?;
+    Ok(())
+}
+
+fn is_fresh(dir: &Path) -> anyhow::Result<bool> {
+    Ok(!dir.join("traces").exists() && !dir.join("artifacts").exists())
+}
+
+fn migrate_0_to_1(dir: &Path) -> anyhow::Result<()> {
+    let artifacts = dir.join("artifacts");
+    if artifacts.exists() {
+        artifacts::migrate_flat_layout(&artifacts)?;
+    }
+    // The index format changed a few times before versioning was introduced.
+    // It'll be rebuilt on demand.
+    let index = dir.join("index");
+    if index.exists() {
+        std::fs::remove_dir_all(&index)?;
+    }
+    Ok(())
+}
+
+/// Only the syntax of traces has changed, so there's nothing to rewrite.  (Some
+/// existing traces may no longer validate, in which case the trace store will
+/// be cold for those jobs.)  The version is bumped anyway, so that older
+/// versions of redux (which would skip the lines they don't understand) refuse
+/// to use the new traces.
+fn syntax_only(_: &Path) -> anyhow::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,353 @@
+//! Caches which can be shared between machines, à la nix's "substituters".
+//! There are two kinds:
+//!
+//! * an HTTP server (`redux.remote`), which is accessed with plain GET and
+//!   PUT requests; and
+//! * a directory on a shared filesystem (`redux.sharedCache`), such as an NFS
+//!   mount or a volume shared between CI jobs.
+//!
+//! Both are laid out in the same way:
+//!
+//! * `traces/<hash>.trace`: a tracefile, named after its hash
+//! * `artifacts/<hash>`: the (uncompressed) contents of a blob
+//! * `jobs/<hash of JobSpec>`: an index of the traces recorded for a job
+//! * `outputs/<content hash>`: an index of the traces which produced a file
+//!   with those contents, along with their jobs
+//!
+//! On an HTTP server an index is a file with one "<trace hash> <job>" line per
+//! entry.  It's updated with a GET followed by a PUT, so concurrent uploads
+//! may lose entries; the worst that can happen is a cache miss.  In a shared
+//! dir an index is a directory with one file per entry, so that writers on
+//! different machines can't clobber each other.
+//!
+//! Everything is checked against its hash after downloading, and traces are
+//! validated locally before they're used.
+
+use crate::{
+    config, filestamp::FileKind, index, trace::JobSpec, trace::Trace, Artifacts, FileStamp,
+    TraceFile,
+};
+use anyhow::{anyhow, Context};
+use blake3::Hash;
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::{debug, info};
+use uuid::Uuid;
+
+/// The operations a cache has to support
+trait Backend {
+    /// `None` means the cache doesn't have it
+    fn get(&self, path: &str) -> anyhow::Result<Option<Box<dyn Read>>>;
+    fn put(&self, path: &str, body: &mut dyn Read) -> anyhow::Result<()>;
+    fn exists(&self, path: &str) -> anyhow::Result<bool>;
+    /// The "<trace hash> <job>" entries of an index
+    fn index_entries(&self, path: &str) -> anyhow::Result<Vec<String>>;
+    fn add_index_entry(&self, path: &str, trace: Hash, job: &JobSpec) -> anyhow::Result<()>;
+}
+
+pub struct Remote {
+    name: String,
+    backend: Box<dyn Backend>,
+    /// Whether to upload the results of jobs we run
+    upload: bool,
+}
+
+impl Remote {
+    /// All configured caches.  Shared dirs come first, since they're cheaper
+    /// to query.
+    pub fn from_config() -> Vec<Remote> {
+        let mut remotes = vec![];
+        for root in config::shared_caches() {
+            remotes.push(Remote {
+                name: root.display().to_string(),
+                backend: Box::new(SharedDir { root }),
+                upload: true,
+            });
+        }
+        if let Some(base) = config::remote() {
+            let agent = ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(5))
+                .build();
+            let base = base.trim_end_matches('/').to_owned();
+            remotes.push(Remote {
+                name: base.clone(),
+                backend: Box::new(Http { base, agent }),
+                upload: config::remote_upload(),
+            });
+        }
+        remotes
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_bytes(&self, path: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(mut body) = self.backend.get(path)? else {
+            return Ok(None);
+        };
+        let mut bytes = vec![];
+        body.read_to_end(&mut bytes)?;
+        Ok(Some(bytes))
+    }
+
+    /// The trace hashes listed in an index
+    fn index_traces(&self, path: &str) -> anyhow::Result<Vec<Hash>> {
+        let mut hashes = vec![];
+        for line in self.backend.index_entries(path)? {
+            let hash = line.split(' ').next().unwrap();
+            hashes.push(
+                hash.parse()
+                    .with_context(|| format!("{}: {path}: Bad entry {line}", self.name))?,
+            );
+        }
+        Ok(hashes)
+    }
+
+    /// Download the traces recorded for `job` into the local trace store,
+    /// along with (recursively) the traces which produced their intermediates,
+    /// so that `DepGraph` can validate them.  Returns the number of traces
+    /// which were new to us.
+    pub fn fetch_traces(&self, job: &JobSpec) -> anyhow::Result<usize> {
+        let mut todo = self.index_traces(&format!("jobs/{}", index::job_key(job)))?;
+        let mut seen = HashSet::<Hash>::default();
+        let mut n = 0;
+        while let Some(hash) = todo.pop() {
+            if !seen.insert(hash) {
+                continue;
+            }
+            let trace = if index::trace_path(hash).exists() {
+                TraceFile::read(&index::trace_path(hash))?.1
+            } else {
+                match self.fetch_trace(hash)? {
+                    Some(x) => {
+                        n += 1;
+                        x
+                    }
+                    None => continue,
+                }
+            };
+            for x in &trace.intermediates {
+                todo.extend(self.index_traces(&format!("outputs/{}", x.hash))?);
+            }
+        }
+        Ok(n)
+    }
+
+    fn fetch_trace(&self, hash: Hash) -> anyhow::Result<Option<Trace>> {
+        let Some(txt) = self.get_bytes(&format!("traces/{hash}.trace"))? else {
+            return Ok(None);
+        };
+        let (job, trace) = index::import_trace(hash, &txt)?;
+        info!("{job}: Fetched trace {hash} from {}", self.name);
+        Ok(Some(trace))
+    }
+
+    /// Download everything needed to restore `file` into the local store
+    pub fn fetch_artifacts(
+        &self,
+        artifacts: &mut Artifacts,
+        file: &FileStamp,
+    ) -> anyhow::Result<()> {
+        self.fetch_blob(artifacts, file.kind, file.hash)
+            .with_context(|| format!("{}: Fetching from {}", file.path, self.name))
+    }
+
+    fn fetch_blob(
+        &self,
+        artifacts: &mut Artifacts,
+        kind: FileKind,
+        hash: Hash,
+    ) -> anyhow::Result<()> {
+        if !artifacts.contains(hash) {
+            let body = self
+                .backend
+                .get(&format!("artifacts/{hash}"))?
+                .ok_or_else(|| anyhow!("{hash}: Not in the cache"))?;
+            artifacts.insert_from(hash, body)?;
+            debug!("{hash}: Fetched from {}", self.name);
+        }
+        if kind == FileKind::Dir {
+            for x in artifacts.read_tree(hash)?.0 {
+                self.fetch_blob(artifacts, x.kind, x.hash)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Upload a trace and its outputs, if this cache is configured to receive
+    /// uploads.  Volatile traces are skipped, since they can't be re-used on
+    /// another machine anyway.
+    pub fn upload(&self, job: &JobSpec, hash: Hash, trace: &Trace) -> anyhow::Result<()> {
+        if !self.upload {
+            return Ok(());
+        }
+        if trace.valid_for.is_some() {
+            debug!("{job}: Not uploading volatile trace");
+            return Ok(());
+        }
+        // Upload the artifacts before the trace, and the trace before the
+        // indexes, so that readers never find a trace whose outputs are missing
+        let artifacts = Artifacts::new()?;
+        for x in &trace.outputs {
+            for hash in artifacts.closure(x.kind, x.hash)? {
+                let path = format!("artifacts/{hash}");
+                if !self.backend.exists(&path)? {
+                    self.backend.put(&path, &mut artifacts.open_blob(hash)?)?;
+                }
+            }
+        }
+        let txt = std::fs::read(index::trace_path(hash))?;
+        self.backend
+            .put(&format!("traces/{hash}.trace"), &mut txt.as_slice())?;
+        self.backend
+            .add_index_entry(&format!("jobs/{}", index::job_key(job)), hash, job)?;
+        for x in &trace.outputs {
+            self.backend
+                .add_index_entry(&format!("outputs/{}", x.hash), hash, job)?;
+        }
+        info!("{job}: Uploaded trace {hash} to {}", self.name);
+        Ok(())
+    }
+}
+
+struct Http {
+    base: String,
+    agent: ureq::Agent,
+}
+
+impl Http {
+    fn url(&self, path: &str) -> String {
+        format!("{}/{path}", self.base)
+    }
+}
+
+impl Backend for Http {
+    fn get(&self, path: &str) -> anyhow::Result<Option<Box<dyn Read>>> {
+        match self.agent.get(&self.url(path)).call() {
+            Ok(resp) => Ok(Some(resp.into_reader())),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(e).context("GET"),
+        }
+    }
+
+    fn put(&self, path: &str, body: &mut dyn Read) -> anyhow::Result<()> {
+        self.agent.put(&self.url(path)).send(body).context("PUT")?;
+        debug!("Uploaded {}", self.url(path));
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> anyhow::Result<bool> {
+        match self.agent.head(&self.url(path)).call() {
+            Ok(_) => Ok(true),
+            Err(ureq::Error::Status(404, _)) => Ok(false),
+            Err(e) => Err(e).context("HEAD"),
+        }
+    }
+
+    fn index_entries(&self, path: &str) -> anyhow::Result<Vec<String>> {
+        let Some(mut body) = self.get(path)? else {
+            return Ok(vec![]);
+        };
+        let mut txt = String::new();
+        body.read_to_string(&mut txt)
+            .with_context(|| self.url(path))?;
+        Ok(txt.lines().map(|x| x.to_owned()).collect())
+    }
+
+    fn add_index_entry(&self, path: &str, trace: Hash, job: &JobSpec) -> anyhow::Result<()> {
+        let mut lines = self.index_entries(path)?;
+        let line = format!("{trace} {job}");
+        if lines.contains(&line) {
+            return Ok(());
+        }
+        lines.push(line);
+        let txt = lines.iter().map(|x| format!("{x}\n")).collect::<String>();
+        self.put(path, &mut txt.as_bytes())
+    }
+}
+
+/// Artifacts are sharded like the local store, since shared filesystems tend
+/// to cope badly with huge directories.
+struct SharedDir {
+    root: PathBuf,
+}
+
+impl SharedDir {
+    fn path(&self, path: &str) -> PathBuf {
+        match path.strip_prefix("artifacts/") {
+            Some(name) if name.len() > 2 => {
+                let (shard, rest) = name.split_at(2);
+                self.root.join("artifacts").join(shard).join(rest)
+            }
+            _ => self.root.join(path),
+        }
+    }
+}
+
+/// Write a file such that concurrent readers (and writers, even on other
+/// machines) only ever see complete files: write it under a unique temporary
+/// name in the same dir, then rename it into place.
+fn write_atomically(to: &Path, body: &mut dyn Read) -> anyhow::Result<()> {
+    let dir = to.parent().unwrap();
+    std::fs::create_dir_all(dir).with_context(|| format!("Creating {}", dir.display()))?;
+    let tmp = dir.join(format!(".{}.tmp", Uuid::new_v4()));
+    let res = File::create_new(&tmp)
+        .and_then(|mut f| {
+            std::io::copy(body, &mut f)?;
+            f.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&tmp, to));
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    res.with_context(|| format!("Writing {}", to.display()))
+}
+
+impl Backend for SharedDir {
+    fn get(&self, path: &str) -> anyhow::Result<Option<Box<dyn Read>>> {
+        let path = self.path(path);
+        match File::open(&path) {
+            Ok(f) => Ok(Some(Box::new(f))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Opening {}", path.display())),
+        }
+    }
+
+    fn put(&self, path: &str, body: &mut dyn Read) -> anyhow::Result<()> {
+        write_atomically(&self.path(path), body)
+    }
+
+    fn exists(&self, path: &str) -> anyhow::Result<bool> {
+        Ok(self.path(path).exists())
+    }
+
+    fn index_entries(&self, path: &str) -> anyhow::Result<Vec<String>> {
+        let dir = self.path(path);
+        let dents = match std::fs::read_dir(&dir) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e).with_context(|| format!("Reading {}", dir.display())),
+        };
+        let mut entries = vec![];
+        for dent in dents {
+            let path = dent?.path();
+            // Skip temporary files
+            if path.extension().is_some() {
+                continue;
+            }
+            entries.push(std::fs::read_to_string(&path)?.trim().to_owned());
+        }
+        Ok(entries)
+    }
+
+    fn add_index_entry(&self, path: &str, trace: Hash, job: &JobSpec) -> anyhow::Result<()> {
+        let entry = self.path(path).join(trace.to_string());
+        write_atomically(&entry, &mut format!("{trace} {job}\n").as_bytes())
+    }
+}
@@ -0,0 +1,330 @@
+//! Building a target as of some other commit, without touching the current
+//! checkout (`redux --rev`).
+//!
+//! The target's dofile is looked up in the commit's tree.  If the target is
+//! checked in at that commit then it's copied straight out of git.  If there's
+//! a trace whose dependencies match the commit's tree then its output is
+//! restored from the cache.  Otherwise it's built in a scratch worktree, which
+//! shares the redux dir (and therefore the build cache) with this one, so any
+//! intermediates which were built before can just be restored.
+//!
+//! Each scratch worktree is flocked while it's in use.  If redux is killed
+//! before it can remove one, the next `--rev` (or `--gc`) cleans it up.
+
+use crate::{
+    depgraph::Checkout,
+    interpreter::Interpreter,
+    redux_dir, replace_path,
+    trace::{JobSpec, Trace},
+    Artifacts, DepGraph, FileKind, FileStamp, GlobStamp, LocalPath, RuleSet, ENV_VAR_TRACEFILE,
+    REPO,
+};
+use anyhow::{bail, ensure, Context};
+use gix::bstr::ByteSlice;
+use rustix::fs::{flock, FlockOperation};
+use std::{
+    collections::HashMap,
+    fs::File,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::LazyLock,
+};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+static WORKTREES_DIR: LazyLock<PathBuf> = LazyLock::new(|| redux_dir().join("worktrees"));
+
+/// Build `target` as it would be at `rev`, and write it to `out`
+pub fn build_at_rev(rev: &str, target: &LocalPath, out: &Path) -> anyhow::Result<()> {
+    let repo = REPO.to_thread_local();
+    let commit = repo
+        .rev_parse_single(rev)
+        .with_context(|| format!("Resolving {rev}"))?
+        .object()?
+        .peel_to_kind(gix::object::Kind::Commit)?;
+    let tree = commit.clone().peel_to_tree()?;
+
+    let mut buf = vec![];
+    if let Some(entry) = tree.lookup_entry_by_path(target.as_path(), &mut buf)? {
+        let mode = entry.mode();
+        if mode.is_blob_or_symlink() {
+            info!("{target}: Checked in at {rev}");
+            let blob = entry.object()?;
+            return write_blob(&blob.data, mode, out);
+        }
+    }
+
+    let rules = RuleSet::scan_tree(&tree)?;
+    let Some(job) = rules.job_for(target.clone()) else {
+        bail!("{target}: No rule found at {rev}");
+    };
+    debug!("Found rule {} at {rev}", job.rule);
+
+    let checkout = CommitCheckout::new(&repo, &tree, rules)?;
+    if let Some(tree) = DepGraph::load_for(&job)?.valid_trace_at(&checkout, &job) {
+        let artifacts = Artifacts::new()?;
+        let output = tree.outputs.iter().find(|x| x.path == *target);
+        if let Some(output) = output.filter(|x| artifacts.contains_stamp(x)) {
+            info!("{target}: Found an existing trace whose sources match {rev}");
+            info!("{tree}");
+            return artifacts.restore_to(output, out);
+        }
+    }
+
+    remove_stale_worktrees()?;
+    let worktree = Worktree::add(commit.id)?;
+    let status = Command::new(std::env::current_exe()?)
+        .arg(target.as_path())
+        .current_dir(&worktree.path)
+        // This isn't part of whichever job we might be running in
+        .env_remove(ENV_VAR_TRACEFILE)
+        .status()?;
+    ensure!(status.success(), "{target}: Building at {rev} failed");
+    let built = worktree.path.join(target.as_path());
+    if let Err(e) = replace_path(&built, out) {
+        debug!("Can't move {} ({e:#}); copying instead", built.display());
+        // Copy next to `out` first, so it's still replaced atomically
+        let tmp = out.with_file_name(format!(".redux_{}.copy", Uuid::new_v4()));
+        let res = copy_path(&built, &tmp)
+            .with_context(|| format!("Copying {} to {}", built.display(), out.display()))
+            .and_then(|()| replace_path(&tmp, out));
+        if res.is_err() && tmp.symlink_metadata().is_ok() {
+            let _ = crate::remove_path(&tmp);
+        }
+        res?;
+    }
+    Ok(())
+}
+
+/// Copy a file, symlink, or directory tree.  Like `git`, only the exec bit of
+/// the mode is preserved.
+fn copy_path(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let meta = std::fs::symlink_metadata(from)?;
+    if meta.is_symlink() {
+        std::os::unix::fs::symlink(std::fs::read_link(from)?, to)?;
+    } else if meta.is_dir() {
+        std::fs::create_dir(to)?;
+        for dent in std::fs::read_dir(from)? {
+            let dent = dent?;
+            copy_path(&dent.path(), &to.join(dent.file_name()))?;
+        }
+    } else {
+        std::fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+/// A commit's tree, for checking traces against (see `Checkout`)
+struct CommitCheckout<'repo> {
+    repo: &'repo gix::Repository,
+    rules: RuleSet,
+    /// Every entry in the tree, including subtrees
+    entries: HashMap<LocalPath, (gix::object::tree::EntryMode, gix::ObjectId)>,
+    /// The paths of the blobs and symlinks, sorted
+    files: Vec<LocalPath>,
+}
+
+impl<'repo> CommitCheckout<'repo> {
+    fn new(
+        repo: &'repo gix::Repository,
+        tree: &gix::Tree,
+        rules: RuleSet,
+    ) -> anyhow::Result<CommitCheckout<'repo>> {
+        let mut recorder = gix::traverse::tree::Recorder::default();
+        tree.traverse().breadthfirst(&mut recorder)?;
+        let mut entries = HashMap::new();
+        let mut files = vec![];
+        for entry in recorder.records {
+            // Non-UTF-8 paths can't be written into a tracefile anyway
+            let Ok(path) = entry.filepath.to_str() else {
+                continue;
+            };
+            let path = path.parse::<LocalPath>()?;
+            if entry.mode.is_blob_or_symlink() {
+                files.push(path.clone());
+            }
+            entries.insert(path, (entry.mode, entry.oid));
+        }
+        files.sort();
+        Ok(CommitCheckout {
+            repo,
+            rules,
+            entries,
+            files,
+        })
+    }
+
+    fn blob(&self, id: gix::ObjectId) -> Option<Vec<u8>> {
+        Some(self.repo.find_object(id).ok()?.detach().data)
+    }
+}
+
+impl Checkout for CommitCheckout<'_> {
+    fn is_source_valid(&self, trace: &Trace, source: &FileStamp) -> bool {
+        let Some(&(mode, id)) = self.entries.get(&source.path) else {
+            return false;
+        };
+        let kind = if mode.is_link() {
+            FileKind::Symlink
+        } else if mode.is_executable() {
+            FileKind::Exec
+        } else if mode.is_blob() {
+            FileKind::File
+        } else {
+            // Directory sources would need hashing the way `tree` does it
+            return false;
+        };
+        if kind != source.kind {
+            return false;
+        }
+        let blob = trace
+            .git_blobs
+            .iter()
+            .find(|(path, _)| *path == source.path);
+        if blob.is_some_and(|(_, x)| *x == id) {
+            return true;
+        }
+        // A symlink's blob is the path it points to, which is what its stamp
+        // hashes too
+        self.blob(id)
+            .is_some_and(|x| blake3::hash(&x) == source.hash)
+    }
+
+    fn is_glob_valid(&self, glob: &GlobStamp, own_outputs: &[LocalPath]) -> bool {
+        glob.is_valid_for(&self.files, own_outputs).unwrap_or(false)
+    }
+
+    fn exists(&self, path: &LocalPath) -> bool {
+        self.entries.contains_key(path)
+    }
+
+    fn interpreter(&self, dofile: &LocalPath) -> Option<String> {
+        let &(mode, id) = self.entries.get(dofile)?;
+        if !mode.is_blob() {
+            return None;
+        }
+        let data = self.blob(id)?;
+        let head = &data[..data.len().min(256)];
+        Some(Interpreter::for_contents(head, mode.is_executable()).to_string())
+    }
+
+    fn is_job_current(&self, job: &JobSpec) -> bool {
+        self.rules.is_job_valid(job)
+    }
+}
+
+fn write_blob(data: &[u8], mode: gix::object::tree::EntryMode, out: &Path) -> anyhow::Result<()> {
+    if mode.is_link() {
+        let pointee = std::str::from_utf8(data)?;
+        std::os::unix::fs::symlink(pointee, out)?;
+    } else {
+        std::fs::write(out, data)?;
+        if mode.is_executable() {
+            let mut perms = std::fs::metadata(out)?.permissions();
+            perms.set_mode(perms.mode() | 0o111);
+            std::fs::set_permissions(out, perms)?;
+        }
+    }
+    Ok(())
+}
+
+/// The top of every worktree which git knows about, including the main one
+/// and any scratch worktrees which are in use
+pub fn worktrees() -> anyhow::Result<Vec<PathBuf>> {
+    let out = Command::new("git")
+        .args(["worktree", "list", "--porcelain", "-z"])
+        .output()
+        .context("Running git worktree list")?;
+    ensure!(out.status.success(), "git worktree list failed");
+    let out = String::from_utf8(out.stdout)?;
+    Ok(out
+        .split('\0')
+        .filter_map(|x| x.strip_prefix("worktree "))
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Remove any scratch worktrees which aren't in use (ie. which were left
+/// behind by a `--rev` which was killed), and make git forget about them
+pub fn remove_stale_worktrees() -> anyhow::Result<()> {
+    let dents = match std::fs::read_dir(&*WORKTREES_DIR) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut removed = false;
+    for dent in dents {
+        let path = dent?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let lock_path = path.with_extension("lock");
+        let lock = File::create(&lock_path)
+            .with_context(|| format!("Creating {}", lock_path.display()))?;
+        if flock(&lock, FlockOperation::NonBlockingLockExclusive).is_err() {
+            continue; // In use
+        }
+        info!("{}: Removing stale worktree", path.display());
+        remove_worktree(&path);
+        if let Err(e) = std::fs::remove_dir_all(&path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("{}: Failed to remove: {e}", path.display());
+            }
+        }
+        let _ = std::fs::remove_file(&lock_path);
+        removed = true;
+    }
+    if removed {
+        let status = Command::new("git").args(["worktree", "prune"]).status();
+        if !status.is_ok_and(|x| x.success()) {
+            warn!("git worktree prune failed");
+        }
+    }
+    Ok(())
+}
+
+fn remove_worktree(path: &Path) -> bool {
+    let status = Command::new("git")
+        .args(["worktree", "remove", "--force"])
+        .arg(path)
+        .status();
+    status.is_ok_and(|x| x.success())
+}
+
+/// A linked worktree, which is removed on drop.  It's flocked for as long as it
+/// exists, so that `remove_stale_worktrees()` leaves it alone.
+struct Worktree {
+    path: PathBuf,
+    _lock: File,
+}
+
+impl Worktree {
+    fn add(commit: gix::ObjectId) -> anyhow::Result<Worktree> {
+        std::fs::create_dir_all(&*WORKTREES_DIR)?;
+        let path = WORKTREES_DIR.join(Uuid::new_v4().to_string());
+        let lock_path = path.with_extension("lock");
+        let lock = File::create(&lock_path)
+            .with_context(|| format!("Creating {}", lock_path.display()))?;
+        flock(&lock, FlockOperation::LockExclusive)
+            .with_context(|| format!("Flocking {}", lock_path.display()))?;
+        info!("Checking out {commit} in {}", path.display());
+        let status = Command::new("git")
+            .args(["worktree", "add", "--quiet", "--detach"])
+            .arg(&path)
+            .arg(commit.to_string())
+            .status()
+            .context("Running git worktree add")?;
+        ensure!(status.success(), "git worktree add failed");
+        Ok(Worktree { path, _lock: lock })
+    }
+}
+
+impl Drop for Worktree {
+    fn drop(&mut self) {
+        if !remove_worktree(&self.path) {
+            error!("{}: Failed to remove worktree", self.path.display());
+        }
+        let _ = std::fs::remove_file(self.path.with_extension("lock"));
+    }
+}
@@ -1,4 +1,5 @@
 use crate::{local_path::project_base, trace::JobSpec, LocalPath};
+use gix::bstr::ByteSlice;
 use globset::{Glob, GlobSet};
 use std::{cmp::Ordering, path::Path};
 use tracing::trace;
@@ -113,11 +114,64 @@ impl RuleSet {
         })
     }
 
+    /// Every dofile which could build `target`, whether or not it exists.
+    /// Highest priority first (see `Rule::priority`).
+    pub fn candidates(target: &LocalPath) -> Vec<LocalPath> {
+        let name = target.file_name();
+        // Longest extension first
+        let extensions = name
+            .match_indices('.')
+            .map(|(i, _)| &name[i..])
+            .chain([""])
+            .collect::<Vec<_>>();
+        let mut paths = vec![];
+        let mut dir = target.parent();
+        loop {
+            paths.push(dir.join(&format!("{name}.do")));
+            for ext in &extensions {
+                paths.push(dir.join(&format!("default{ext}.do")));
+            }
+            if dir.depth() == 0 {
+                break;
+            }
+            dir = dir.parent();
+        }
+        paths
+    }
+
+    /// Like `job_for()`, but without needing the whole rule set: it checks
+    /// which of the target's candidate dofiles exist, rather than walking the
+    /// tree to find every dofile.
+    pub fn lookup(target: LocalPath) -> Option<JobSpec> {
+        Self::lookup_in(project_base(), target)
+    }
+
+    /// Like `lookup()`, but in the worktree at `base`
+    pub fn lookup_in(base: &Path, target: LocalPath) -> Option<JobSpec> {
+        let rule = Self::candidates(&target)
+            .into_iter()
+            .find(|x| base.join(x.as_path()).symlink_metadata().is_ok())?;
+        Some(JobSpec {
+            rule,
+            target,
+            env: vec![],
+        })
+    }
+
     pub fn is_job_valid(&self, job: &JobSpec) -> bool {
         self.job_for(job.target.clone()).as_ref() == Some(job)
     }
 
-    // TODO: Add a variant which scans a tree in the local git repo, instead of the working tree
+    /// Like `is_job_valid()`, but uses `lookup()` instead of a scanned rule set
+    pub fn is_job_current(job: &JobSpec) -> bool {
+        Self::lookup(job.target.clone()).as_ref() == Some(job)
+    }
+
+    /// Like `is_job_current()`, but in the worktree at `base`
+    pub fn is_job_current_in(base: &Path, job: &JobSpec) -> bool {
+        Self::lookup_in(base, job.target.clone()).as_ref() == Some(job)
+    }
+
     pub fn scan_for_do_files() -> anyhow::Result<RuleSet> {
         let mut rules = vec![];
         for ent in walkdir::WalkDir::new(project_base()) {
@@ -131,6 +185,27 @@ impl RuleSet {
         Ok(RuleSet::new(rules))
     }
 
+    /// Like `scan_for_do_files()`, but finds the dofiles in a git tree
+    /// instead of the working tree
+    pub fn scan_tree(tree: &gix::Tree) -> anyhow::Result<RuleSet> {
+        let mut recorder = gix::traverse::tree::Recorder::default();
+        tree.traverse().breadthfirst(&mut recorder)?;
+        let mut rules = vec![];
+        for entry in recorder.records {
+            if entry.mode.is_tree() {
+                continue;
+            }
+            let Ok(path) = entry.filepath.to_str() else {
+                continue;
+            };
+            let Some(rule) = Rule::new(&project_base().join(path)) else {
+                continue;
+            };
+            rules.push(rule);
+        }
+        Ok(RuleSet::new(rules))
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = (&Glob, &LocalPath)> + '_ {
         self.globs.iter().zip(&self.do_files)
     }
@@ -0,0 +1,199 @@
+//! Traces can be signed, so that traces fetched from a shared cache can be
+//! trusted.  Without signatures, anyone who can write to the cache could
+//! publish a trace which maps your sources to an output of their choosing.
+//!
+//! A signed trace ends with the line
+//!
+//! ```text
+//! signature <public key> <signature>
+//! ```
+//!
+//! where the signature is an ed25519 signature over everything before that
+//! line, and both are hex-encoded.  Since the signature is part of the trace,
+//! it travels with it to and from remotes and bundles without any extra work.
+//!
+//! If `redux.signingKey` is set, every trace redux records is signed with that
+//! key.  If `redux.trustedKey` is set, traces which aren't signed by one of
+//! those keys (or by our own signing key) are ignored.
+
+use crate::config;
+use anyhow::{anyhow, Context};
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use std::{fmt, io::Write, os::unix::fs::OpenOptionsExt, path::Path, str::FromStr};
+use tracing::warn;
+
+/// The contents of a `signature` line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub key: VerifyingKey,
+    pub sig: ed25519_dalek::Signature,
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}",
+            hex::encode(self.key.as_bytes()),
+            hex::encode(self.sig.to_bytes()),
+        )
+    }
+}
+
+impl FromStr for Signature {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, sig) = s.split_once(' ').ok_or_else(|| anyhow!("No space"))?;
+        let sig = hex::decode(sig)?;
+        Ok(Signature {
+            key: parse_key(key)?,
+            sig: ed25519_dalek::Signature::from_slice(&sig)?,
+        })
+    }
+}
+
+fn parse_key(s: &str) -> anyhow::Result<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(s.trim())?
+        .try_into()
+        .map_err(|_| anyhow!("Expected 32 bytes"))?;
+    Ok(VerifyingKey::from_bytes(&bytes)?)
+}
+
+/// Sign the contents of a tracefile.  The result should be appended to it as a
+/// `signature` line.
+pub fn sign(txt: &[u8], key: &SigningKey) -> Signature {
+    Signature {
+        key: key.verifying_key(),
+        sig: key.sign(txt),
+    }
+}
+
+/// Read the key named by `redux.signingKey`, if it's set
+pub fn signing_key() -> anyhow::Result<Option<SigningKey>> {
+    let Some(path) = config::signing_key() else {
+        return Ok(None);
+    };
+    let txt = std::fs::read_to_string(&path)
+        .with_context(|| format!("Reading signing key {}", path.display()))?;
+    let bytes: [u8; 32] = hex::decode(txt.trim())
+        .ok()
+        .and_then(|x| x.try_into().ok())
+        .ok_or_else(|| anyhow!("{}: Not a valid signing key", path.display()))?;
+    Ok(Some(SigningKey::from_bytes(&bytes)))
+}
+
+/// Create a new signing key at `path`, readable only by the current user.
+/// Returns its public half.
+pub fn generate_key(path: &Path) -> anyhow::Result<VerifyingKey> {
+    let mut seed = [0; 32];
+    getrandom::getrandom(&mut seed).map_err(|e| anyhow!("Generating a key: {e}"))?;
+    let key = SigningKey::from_bytes(&seed);
+    let mut f = std::fs::File::options()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Creating {}", path.display()))?;
+    writeln!(f, "{}", hex::encode(seed))?;
+    Ok(key.verifying_key())
+}
+
+/// What we make of a tracefile's signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureStatus {
+    Unsigned,
+    /// The signature line is malformed, or doesn't match the contents
+    BadSignature,
+    Untrusted(VerifyingKey),
+    Trusted(VerifyingKey),
+}
+
+impl fmt::Display for SignatureStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureStatus::Unsigned => write!(f, "Unsigned"),
+            SignatureStatus::BadSignature => write!(f, "Bad signature"),
+            SignatureStatus::Untrusted(key) => {
+                write!(f, "Signed by untrusted key {}", hex::encode(key.as_bytes()))
+            }
+            SignatureStatus::Trusted(key) => {
+                write!(f, "Signed by {}", hex::encode(key.as_bytes()))
+            }
+        }
+    }
+}
+
+/// The keys listed in `redux.trustedKey`, plus the public half of
+/// `redux.signingKey`.  `None` means that no trust list is configured, in which
+/// case any trace is accepted (so long as it isn't badly signed).
+pub struct TrustList(Option<Vec<VerifyingKey>>);
+
+impl TrustList {
+    pub fn from_config() -> TrustList {
+        let keys = config::trusted_keys();
+        if keys.is_empty() {
+            return TrustList(None);
+        }
+        let mut trusted = vec![];
+        for x in keys {
+            match parse_key(&x) {
+                Ok(key) => trusted.push(key),
+                Err(e) => warn!("redux.trustedKey: Ignoring {x:?}: {e:#}"),
+            }
+        }
+        match signing_key() {
+            Ok(Some(key)) => trusted.push(key.verifying_key()),
+            Ok(None) => (),
+            Err(e) => warn!("{e:#}"),
+        }
+        TrustList(Some(trusted))
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub fn check(&self, txt: &[u8]) -> SignatureStatus {
+        let (body, line) = split_signature(txt);
+        let Some(line) = line else {
+            return SignatureStatus::Unsigned;
+        };
+        let sig = std::str::from_utf8(line)
+            .map_err(anyhow::Error::from)
+            .and_then(|x| x.parse::<Signature>());
+        let Ok(sig) = sig else {
+            return SignatureStatus::BadSignature;
+        };
+        if sig.key.verify_strict(body, &sig.sig).is_err() {
+            return SignatureStatus::BadSignature;
+        }
+        match &self.0 {
+            Some(keys) if keys.contains(&sig.key) => SignatureStatus::Trusted(sig.key),
+            _ => SignatureStatus::Untrusted(sig.key),
+        }
+    }
+
+    /// Should a trace with this status be used?
+    pub fn accepts(&self, status: SignatureStatus) -> bool {
+        match status {
+            SignatureStatus::BadSignature => false,
+            SignatureStatus::Trusted(_) => true,
+            SignatureStatus::Unsigned | SignatureStatus::Untrusted(_) => self.0.is_none(),
+        }
+    }
+}
+
+/// Split a tracefile into the part covered by the signature and the contents
+/// of the `signature` line (if it has one)
+fn split_signature(txt: &[u8]) -> (&[u8], Option<&[u8]>) {
+    let trimmed = txt.strip_suffix(b"\n").unwrap_or(txt);
+    let start = trimmed
+        .iter()
+        .rposition(|&x| x == b'\n')
+        .map_or(0, |i| i + 1);
+    match trimmed[start..].strip_prefix(b"signature ") {
+        Some(line) => (&txt[..start], Some(line)),
+        None => (txt, None),
+    }
+}
@@ -0,0 +1,263 @@
+//! Counters for judging how well the cache is working.
+//!
+//! Every redux process taking part in a build appends one line per job to
+//! `stats/<build id>`:
+//!
+//! ```text
+//! <timestamp> <outcome> <job>
+//! ```
+//!
+//! where `<outcome>` is one of `restored`, `ran`, `bailed_out` or `cut_off`.
+//! `stats()` summarises these, along with the current state of the store.
+
+use crate::{index, redux_dir, trace::JobSpec, Artifacts, BuildId, TraceFile, TRACES_DIR};
+use anyhow::{anyhow, bail, Context};
+use serde::Serialize;
+use std::{
+    collections::BTreeSet, fs::File, io::Write, path::PathBuf, str::FromStr, sync::LazyLock,
+    time::SystemTime,
+};
+use tracing::{debug, warn};
+
+pub static STATS_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
+    let path = redux_dir().join("stats");
+    std::fs::create_dir_all(&path).unwrap();
+    path
+});
+
+/// What happened when a job was requested
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Its outputs were restored from the cache
+    Restored,
+    /// It was run, and produced a new output
+    Ran,
+    /// It was run, but exited with 102 once its outputs had been restored
+    BailedOut,
+    /// It was run, but produced the same output as a previous run, so jobs
+    /// which depend on it may not need to be
+    CutOff,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Restored => "restored",
+            Outcome::Ran => "ran",
+            Outcome::BailedOut => "bailed_out",
+            Outcome::CutOff => "cut_off",
+        }
+    }
+}
+
+impl FromStr for Outcome {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "restored" => Outcome::Restored,
+            "ran" => Outcome::Ran,
+            "bailed_out" => Outcome::BailedOut,
+            "cut_off" => Outcome::CutOff,
+            _ => bail!("Unknown outcome {s:?}"),
+        })
+    }
+}
+
+/// Record the outcome of a job against the current build.  Stats are only
+/// informational, so this never fails the build.
+pub fn record(outcome: Outcome, job: &JobSpec) {
+    let go = || -> anyhow::Result<()> {
+        let id = BuildId::current_or_new()?;
+        let path = STATS_DIR.join(id.0.to_string());
+        let mut f = File::options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Opening {}", path.display()))?;
+        // Every job in the build appends to this file.  Lines are much shorter
+        // than PIPE_BUF, so as long as each one is written in one go,
+        // concurrent appends won't interleave.
+        let now = humantime::Timestamp::from(SystemTime::now());
+        f.write_all(format!("{now} {} {job}\n", outcome.as_str()).as_bytes())?;
+        Ok(())
+    };
+    if let Err(e) = go() {
+        warn!("Recording stats: {e:#}");
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct Counters {
+    pub restored: usize,
+    pub ran: usize,
+    pub bailed_out: usize,
+    pub cut_off: usize,
+}
+
+impl Counters {
+    fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Restored => self.restored += 1,
+            Outcome::Ran => self.ran += 1,
+            Outcome::BailedOut => self.bailed_out += 1,
+            Outcome::CutOff => self.cut_off += 1,
+        }
+    }
+
+    fn merge(&mut self, other: Counters) {
+        self.restored += other.restored;
+        self.ran += other.ran;
+        self.bailed_out += other.bailed_out;
+        self.cut_off += other.cut_off;
+    }
+
+    pub fn total(&self) -> usize {
+        self.restored + self.ran + self.bailed_out + self.cut_off
+    }
+
+    /// The fraction of jobs which didn't need to be run at all
+    pub fn hit_rate(&self) -> Option<f64> {
+        let total = self.total();
+        (total > 0).then(|| self.restored as f64 / total as f64)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildStats {
+    pub id: String,
+    /// When the first job of the build finished (RFC 3339)
+    pub started: String,
+    #[serde(flatten)]
+    pub counters: Counters,
+    pub hit_rate: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ArtifactSize {
+    pub hash: String,
+    /// The space it takes up in the store (ie. after compression)
+    pub bytes: u64,
+    /// Where it's been produced, if it's the output of a job (rather than
+    /// something inside an output directory, say)
+    pub paths: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StoreStats {
+    pub traces: usize,
+    pub trace_bytes: u64,
+    pub volatile_traces: usize,
+    pub artifacts: usize,
+    pub artifact_bytes: u64,
+    pub largest_artifacts: Vec<ArtifactSize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Stats {
+    /// The most recent builds, oldest first
+    pub builds: Vec<BuildStats>,
+    /// Summed over every recorded build, not just the ones listed
+    pub total: Counters,
+    pub total_hit_rate: Option<f64>,
+    pub store: StoreStats,
+}
+
+/// Summarise the `n_builds` most recent builds, and the current contents of
+/// the store (including the `n_largest` largest artifacts)
+pub fn stats(n_builds: usize, n_largest: usize) -> anyhow::Result<Stats> {
+    let mut builds = vec![];
+    let mut total = Counters::default();
+    for dent in std::fs::read_dir(&*STATS_DIR)? {
+        let path = dent?.path();
+        let txt = std::fs::read_to_string(&path)
+            .with_context(|| format!("Reading {}", path.display()))?;
+        let mut counters = Counters::default();
+        let mut started = None::<SystemTime>;
+        for line in txt.lines() {
+            let parsed = (|| {
+                let mut parts = line.splitn(3, ' ');
+                let t: SystemTime = parts
+                    .next()
+                    .unwrap()
+                    .parse::<humantime::Timestamp>()?
+                    .into();
+                let outcome: Outcome = parts.next().ok_or_else(|| anyhow!("Truncated"))?.parse()?;
+                anyhow::Ok((t, outcome))
+            })();
+            match parsed {
+                Ok((t, outcome)) => {
+                    counters.add(outcome);
+                    if started.is_none_or(|x| t < x) {
+                        started = Some(t);
+                    }
+                }
+                Err(e) => warn!("{}: Bad line {line:?}: {e:#}", path.display()),
+            }
+        }
+        let Some(started) = started else { continue };
+        total.merge(counters);
+        let id = path.file_name().unwrap().to_string_lossy().into_owned();
+        builds.push((started, id, counters));
+    }
+    builds.sort_by_key(|x| x.0);
+    let builds = builds[builds.len().saturating_sub(n_builds)..]
+        .iter()
+        .map(|(started, id, counters)| BuildStats {
+            id: id.clone(),
+            started: humantime::format_rfc3339_seconds(*started).to_string(),
+            counters: *counters,
+            hit_rate: counters.hit_rate(),
+        })
+        .collect();
+
+    Ok(Stats {
+        builds,
+        total,
+        total_hit_rate: total.hit_rate(),
+        store: store_stats(n_largest)?,
+    })
+}
+
+fn store_stats(n_largest: usize) -> anyhow::Result<StoreStats> {
+    let mut stats = StoreStats::default();
+    for dent in std::fs::read_dir(&*TRACES_DIR)? {
+        let path = dent?.path();
+        if index::parse_trace_path(&path).is_none() {
+            continue;
+        }
+        stats.traces += 1;
+        stats.trace_bytes += std::fs::metadata(&path)?.len();
+        match TraceFile::read(&path) {
+            Ok((_, trace)) if trace.valid_for.is_some() || trace.valid_until.is_some() => {
+                stats.volatile_traces += 1;
+            }
+            Ok(_) => (),
+            Err(e) => debug!("{e:#}"),
+        }
+    }
+
+    let mut blobs = vec![];
+    for blob in Artifacts::new()?.blobs()? {
+        let bytes = std::fs::metadata(&blob.path)?.len();
+        stats.artifacts += 1;
+        stats.artifact_bytes += bytes;
+        blobs.push((bytes, blob.hash));
+    }
+    blobs.sort_by_key(|x| std::cmp::Reverse(x.0));
+    for (bytes, hash) in blobs.into_iter().take(n_largest) {
+        let mut paths = BTreeSet::new();
+        for (_, trace) in index::traces_producing(hash)? {
+            if let Ok((_, trace)) = TraceFile::read(&index::trace_path(trace)) {
+                let outputs = trace.outputs.into_iter().filter(|x| x.hash == hash);
+                paths.extend(outputs.map(|x| x.path.to_string()));
+            }
+        }
+        stats.largest_artifacts.push(ArtifactSize {
+            hash: hash.to_string(),
+            bytes,
+            paths: paths.into_iter().collect(),
+        });
+    }
+    Ok(stats)
+}
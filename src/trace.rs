@@ -1,4 +1,9 @@
-use crate::{BuildId, FileStamp, LocalPath, RuleSet, ENV_VAR_TRACEFILE};
+use crate::{
+    git_index,
+    glob::GlobStamp,
+    signing::{self, Signature, TrustList},
+    BuildId, FileStamp, LocalPath, RuleSet, ENV_VAR_TRACEFILE,
+};
 use anyhow::{anyhow, bail, Context};
 use rustix::fs::{flock, FlockOperation};
 use std::{
@@ -9,7 +14,7 @@ use std::{
     str::FromStr,
     time::SystemTime,
 };
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, PartialOrd, Ord)]
 pub struct JobSpec {
@@ -51,8 +56,7 @@ impl FromStr for JobSpec {
 impl JobSpec {
     pub fn fancy(&self) -> String {
         use yansi::Paint;
-        let rules = RuleSet::scan_for_do_files().unwrap();
-        let is_valid = rules.is_job_valid(self);
+        let is_valid = RuleSet::is_job_current(self);
         let txt = format!("{:.8}", self);
         format!("{}", if is_valid { txt.magenta() } else { txt.red() })
     }
@@ -99,8 +103,30 @@ pub struct Trace {
     pub sources: Vec<FileStamp>,
     pub intermediates: Vec<FileStamp>,
     pub outputs: Vec<FileStamp>,
+    /// Extra outputs which the job declared with `--also-produces`, in addition
+    /// to its target.  Once the job has finished, each of these also appears
+    /// in `outputs`.
+    pub declared_outputs: Vec<LocalPath>,
     pub valid_for: Option<BuildId>,
     pub valid_until: Option<SystemTime>,
+    /// Which files matched some patterns.  See `glob`.
+    pub globs: Vec<GlobStamp>,
+    /// Files which must not exist (`redux --ifcreate`)
+    pub absent: Vec<LocalPath>,
+    /// What the dofile was run with.  See `interpreter`.
+    pub interpreter: Option<String>,
+    /// Whether the job's stdout was used as its output (if it didn't write to
+    /// `$3`).  See `config::capture_stdout`.
+    pub capture_stdout: bool,
+    /// The git blob IDs of some of the sources.  See `git_index`.
+    pub git_blobs: Vec<(LocalPath, gix::ObjectId)>,
+    /// The version of redux which recorded this trace.  `None` for traces
+    /// recorded before this was tracked.
+    pub redux_version: Option<String>,
+    /// How many lines couldn't be parsed.  Such a trace is never considered
+    /// valid: a line we don't understand (written by a newer redux, say) may
+    /// be a dependency, and ignoring it could mean restoring stale outputs.
+    pub unparseable_lines: usize,
 }
 
 impl fmt::Display for Trace {
@@ -137,10 +163,18 @@ impl fmt::Display for Trace {
 impl Trace {
     fn merge(&mut self, line: TraceFileLine) {
         match line {
-            TraceFileLine::Job(_) => (),
+            // Checked against the raw text; see `signing`
+            TraceFileLine::Job(_) | TraceFileLine::Signature(_) => (),
+            TraceFileLine::ReduxVersion(x) => self.redux_version = Some(x),
             TraceFileLine::Source(x) => self.sources.push(x),
+            TraceFileLine::GitBlob(path, id) => self.git_blobs.push((path, id)),
+            TraceFileLine::Glob(x) => self.globs.push(x),
+            TraceFileLine::Absent(x) => self.absent.push(x),
+            TraceFileLine::Interpreter(x) => self.interpreter = Some(x),
+            TraceFileLine::CaptureStdout => self.capture_stdout = true,
             TraceFileLine::Generated(x) => self.intermediates.push(x),
             TraceFileLine::Produced(x) => self.outputs.push(x),
+            TraceFileLine::AlsoProduces(x) => self.declared_outputs.push(x),
             TraceFileLine::EnvVar(x) => self.env_vars.push(x),
             TraceFileLine::Data(x) => self.data.push(x),
             TraceFileLine::ValidFor(x) => self.valid_for = Some(x),
@@ -153,26 +187,67 @@ impl Trace {
         }
     }
 
-    fn parse(txt: &str) -> anyhow::Result<Trace> {
+    /// Whether `source` (one of this trace's sources) is unchanged.  If we know
+    /// its git blob ID, git's index may be able to tell us without reading it.
+    pub fn is_source_valid(&self, source: &FileStamp) -> bool {
+        let blob = self.git_blobs.iter().find(|(path, _)| *path == source.path);
+        if blob.is_some_and(|(_, id)| git_index::is_unchanged(source, *id)) {
+            return true;
+        }
+        source.is_valid().unwrap_or(false)
+    }
+
+    /// Whether this trace lists (as `absent`) every dofile which would take
+    /// over from `job`'s rule.  If so, validating the trace is enough to know
+    /// that the rule is still the one which applies, without rescanning the
+    /// rules.
+    pub fn records_candidates(&self, job: &JobSpec) -> bool {
+        RuleSet::candidates(&job.target)
+            .iter()
+            .take_while(|x| **x != job.rule)
+            .all(|x| self.absent.contains(x))
+    }
+
+    /// Lines which can't be parsed are skipped, and returned alongside the
+    /// trace
+    fn parse(txt: &str) -> (Trace, Vec<anyhow::Error>) {
         let mut trace = Trace::default();
-        for line in txt.lines() {
+        let mut errors = vec![];
+        for (i, line) in txt.lines().enumerate() {
             match line.parse() {
                 Ok(line) => trace.merge(line),
-                Err(e) => warn!("{e}"),
+                // +2 because the job line has already been stripped off
+                Err(e) => errors.push(e.context(format!("line {}", i + 2))),
             }
         }
-        Ok(trace)
+        trace.unparseable_lines = errors.len();
+        (trace, errors)
     }
 }
 
 pub enum TraceFileLine {
     Job(JobSpec),
+    /// The version of redux which recorded the trace
+    ReduxVersion(String),
     /// Needed, but not generated
     Source(FileStamp),
+    /// The git blob ID of the preceding source
+    GitBlob(LocalPath, gix::ObjectId),
+    /// The set of files matching a pattern
+    Glob(GlobStamp),
+    /// The job would have behaved differently if this file existed
+    Absent(LocalPath),
+    /// The program which ran the dofile, and its arguments
+    Interpreter(String),
+    /// The job's stdout was captured, and used as its output if it didn't
+    /// write to `$3`
+    CaptureStdout,
     /// Needed, and generated
     Generated(FileStamp),
-    /// The output of the job
+    /// An output of the job.  The first one is the job's target.
     Produced(FileStamp),
+    /// The job will also produce this file (a "side output")
+    AlsoProduces(LocalPath),
     EnvVar(EnvVar),
     Data(blake3::Hash),
     // Data(),
@@ -182,21 +257,31 @@ pub enum TraceFileLine {
     /// Job was non-deterministic and must be re-run, even if the sources/
     /// intermediates are up-to-date
     ValidUntil(SystemTime),
+    /// Signs everything before it.  Always the last line.
+    Signature(Signature),
 }
 
 impl fmt::Display for TraceFileLine {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             TraceFileLine::Job(x) => write!(f, "job {x}"),
+            TraceFileLine::ReduxVersion(x) => write!(f, "redux_version {x}"),
             TraceFileLine::Source(x) => write!(f, "source {x}"),
+            TraceFileLine::GitBlob(path, id) => write!(f, "git_blob {id} {path}"),
+            TraceFileLine::Glob(x) => write!(f, "glob {x}"),
+            TraceFileLine::Absent(x) => write!(f, "absent {x}"),
+            TraceFileLine::Interpreter(x) => write!(f, "interpreter {x}"),
+            TraceFileLine::CaptureStdout => write!(f, "capture_stdout"),
             TraceFileLine::Generated(x) => write!(f, "generated {x}"),
             TraceFileLine::Produced(x) => write!(f, "produced {x}"),
+            TraceFileLine::AlsoProduces(x) => write!(f, "also_produces {x}"),
             TraceFileLine::EnvVar(x) => write!(f, "env_var {x}"),
             TraceFileLine::Data(x) => write!(f, "data {x}"),
             TraceFileLine::ValidFor(x) => write!(f, "valid_for {}", x.0),
             TraceFileLine::ValidUntil(x) => {
                 write!(f, "valid_until {}", humantime::Timestamp::from(*x))
             }
+            TraceFileLine::Signature(x) => write!(f, "signature {x}"),
         }
     }
 }
@@ -207,13 +292,24 @@ impl FromStr for TraceFileLine {
     fn from_str(line: &str) -> Result<Self, Self::Err> {
         let (x, y) = line.split_once(' ').unwrap_or((line, ""));
         Ok(match x {
+            "redux_version" => TraceFileLine::ReduxVersion(y.to_owned()),
             "source" => TraceFileLine::Source(y.parse()?),
+            "glob" => TraceFileLine::Glob(y.parse()?),
+            "absent" => TraceFileLine::Absent(y.parse()?),
+            "interpreter" => TraceFileLine::Interpreter(y.to_owned()),
+            "capture_stdout" => TraceFileLine::CaptureStdout,
+            "git_blob" => {
+                let (id, path) = y.split_once(' ').ok_or_else(|| anyhow!("No path"))?;
+                TraceFileLine::GitBlob(path.parse()?, id.parse()?)
+            }
             "generated" => TraceFileLine::Generated(y.parse()?),
             "produced" => TraceFileLine::Produced(y.parse()?),
+            "also_produces" => TraceFileLine::AlsoProduces(y.parse()?),
             "env_var" => TraceFileLine::EnvVar(y.parse()?),
             "data" => TraceFileLine::Data(y.parse()?),
             "valid_for" => TraceFileLine::ValidFor(BuildId(y.parse()?)),
             "valid_until" => TraceFileLine::ValidUntil(y.parse::<humantime::Timestamp>()?.into()),
+            "signature" => TraceFileLine::Signature(y.parse()?),
             _ => bail!("Unknown line in tracefile: {}", x),
         })
     }
@@ -257,7 +353,7 @@ impl TraceFile {
             target.with_file_name(format!(".redux_{}.trace", filename))
         };
         let parent = path.parent().unwrap();
-        std::fs::create_dir_all(&parent)
+        std::fs::create_dir_all(parent)
             .with_context(|| format!("Creating dir {}", parent.display()))?;
 
         // Try to create the tracefile
@@ -302,24 +398,84 @@ impl TraceFile {
         writeln!(
             f,
             "{}",
-            TraceFileLine::Source(FileStamp::new(job.rule.clone())?)
+            TraceFileLine::ReduxVersion(env!("CARGO_PKG_VERSION").to_owned())
         )?;
+        let rule = FileStamp::new(job.rule.clone())?;
+        let blob = git_index::blob_line(&rule);
+        writeln!(f, "{}", TraceFileLine::Source(rule))?;
+        if let Some(line) = blob {
+            writeln!(f, "{line}")?;
+        }
+        // If any of these are created, they'll take over from the rule we're
+        // using.  Recording them means we don't have to rescan the rules to
+        // find out.
+        let shadowing = RuleSet::candidates(&job.target)
+            .into_iter()
+            .take_while(|x| *x != job.rule);
+        for path in shadowing {
+            writeln!(f, "{}", TraceFileLine::Absent(path))?;
+        }
         Ok(Some(TraceFile { path, job }))
     }
 
-    pub fn finish(&self, output: FileStamp) -> anyhow::Result<()> {
-        TraceFile::append(Some(self), TraceFileLine::Produced(output))
+    /// `outputs` should start with the job's target.  If `redux.signingKey` is
+    /// set, the trace is signed too, so nothing may be appended afterwards.
+    pub fn finish(&self, outputs: Vec<FileStamp>) -> anyhow::Result<()> {
+        for x in outputs {
+            TraceFile::append(Some(self), TraceFileLine::Produced(x))?;
+        }
+        if let Some(key) = signing::signing_key()? {
+            let txt = std::fs::read(&self.path)?;
+            TraceFile::append(
+                Some(self),
+                TraceFileLine::Signature(signing::sign(&txt, &key)),
+            )?;
+        }
+        Ok(())
     }
 
     pub fn read(path: &Path) -> anyhow::Result<(JobSpec, Trace)> {
         let txt =
             std::fs::read_to_string(path).with_context(|| format!("Reading {}", path.display()))?;
-        let (job, trace) = txt.split_once('\n').unwrap();
-        let job = job.trim_start_matches("job ").parse()?;
-        let trace = Trace::parse(trace)?;
+        TraceFile::parse_logging_errors(path, &txt)
+    }
+
+    /// Like `read()`, but returns `None` if `trust` doesn't accept the trace's
+    /// signature
+    pub fn read_trusted(
+        path: &Path,
+        trust: &TrustList,
+    ) -> anyhow::Result<Option<(JobSpec, Trace)>> {
+        let txt =
+            std::fs::read_to_string(path).with_context(|| format!("Reading {}", path.display()))?;
+        let status = trust.check(txt.as_bytes());
+        if !trust.accepts(status) {
+            debug!("{}: {status}; ignoring", path.display());
+            return Ok(None);
+        }
+        TraceFile::parse_logging_errors(path, &txt).map(Some)
+    }
+
+    fn parse_logging_errors(path: &Path, txt: &str) -> anyhow::Result<(JobSpec, Trace)> {
+        let (job, trace, errors) =
+            TraceFile::parse(txt).with_context(|| format!("Parsing {}", path.display()))?;
+        for e in errors {
+            warn!("{}: {e:#}", path.display());
+        }
         Ok((job, trace))
     }
 
+    /// Parse the contents of a tracefile.  Lines which can't be parsed are
+    /// skipped, and returned separately.
+    pub fn parse(txt: &str) -> anyhow::Result<(JobSpec, Trace, Vec<anyhow::Error>)> {
+        let (job, trace) = txt
+            .split_once('\n')
+            .ok_or_else(|| anyhow!("Tracefile is empty"))?;
+        let job = job.trim_start_matches("job ").parse()?;
+        let (trace, errors) = Trace::parse(trace);
+        Ok((job, trace, errors))
+    }
+
     pub fn current() -> anyhow::Result<Option<TraceFile>> {
         match std::env::var(ENV_VAR_TRACEFILE) {
             Err(std::env::VarError::NotPresent) => Ok(None),
@@ -341,7 +497,9 @@ impl TraceFile {
             // TODO: Take a lock on the tracefile before writing?
             let mut file = File::options().append(true).open(path)?;
             writeln!(file, "{}", txt)?;
-            println!("{}: {}", job.target, txt);
+            // Not stdout: that may be the job's output (see
+            // `config::capture_stdout`)
+            eprintln!("{}: {}", job.target, txt);
         } else {
             println!("{}", txt);
         }
@@ -0,0 +1,131 @@
+//! Directories are stored in the artifact store as "tree objects", à la git.  A
+//! tree object lists the entries of a directory, sorted by name, one per line:
+//!
+//! ```text
+//! <kind> <hash> <name>
+//! ```
+//!
+//! where `<kind>` is `file`, `exec`, `link` or `tree`, and `<hash>` is the hash
+//! of the file's contents, the symlink's target, or the subdirectory's tree
+//! object.  The hash of a directory is the hash of its tree object, so it
+//! changes whenever anything inside the directory changes.
+
+use crate::{
+    filestamp::{read_symlink, FileKind},
+    hash_cache,
+};
+use anyhow::{anyhow, bail, Context};
+use blake3::Hash;
+use std::{fmt, path::Path, str::FromStr};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeEntry {
+    pub name: String,
+    pub kind: FileKind,
+    pub hash: Hash,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tree(pub Vec<TreeEntry>);
+
+/// Something encountered while hashing a directory
+pub enum Visit<'a> {
+    File(&'a Path, Hash),
+    /// The target of a symlink, or the serialized tree object of a directory
+    Bytes(&'a [u8], Hash),
+}
+
+impl fmt::Display for Tree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for x in &self.0 {
+            let kind = match x.kind {
+                FileKind::File => "file",
+                FileKind::Exec => "exec",
+                FileKind::Symlink => "link",
+                FileKind::Dir => "tree",
+            };
+            writeln!(f, "{kind} {} {}", x.hash, x.name)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Tree {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut entries = vec![];
+        for line in s.lines() {
+            let mut parts = line.splitn(3, ' ');
+            let (Some(kind), Some(hash), Some(name)) = (parts.next(), parts.next(), parts.next())
+            else {
+                bail!("Bad line in tree object: {line}");
+            };
+            let kind = match kind {
+                "file" => FileKind::File,
+                "exec" => FileKind::Exec,
+                "link" => FileKind::Symlink,
+                "tree" => FileKind::Dir,
+                _ => bail!("Unknown kind in tree object: {kind}"),
+            };
+            check_name(name)?;
+            entries.push(TreeEntry {
+                name: name.to_owned(),
+                kind,
+                hash: hash.parse()?,
+            });
+        }
+        Ok(Tree(entries))
+    }
+}
+
+/// Don't let a (possibly malicious) tree object write outside its directory
+fn check_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\n']) {
+        bail!("Invalid name in tree object: {name:?}");
+    }
+    Ok(())
+}
+
+/// Hash a directory, recursively.  `visit` is called on every file, and on the
+/// tree object of every directory (including `dir` itself), so that the caller
+/// can store them.
+pub fn hash_dir(
+    dir: &Path,
+    visit: &mut dyn FnMut(Visit) -> anyhow::Result<()>,
+) -> anyhow::Result<Hash> {
+    let mut ents = std::fs::read_dir(dir)
+        .with_context(|| format!("Reading dir {}", dir.display()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    ents.sort_by_key(|x| x.file_name());
+    let mut tree = Tree::default();
+    for ent in ents {
+        let path = ent.path();
+        let name = ent
+            .file_name()
+            .into_string()
+            .map_err(|x| anyhow!("{}: Non-UTF-8 filename", x.to_string_lossy()))?;
+        check_name(&name)?;
+        let meta = std::fs::symlink_metadata(&path)?;
+        let kind = FileKind::of(&meta);
+        let hash = match kind {
+            FileKind::File | FileKind::Exec => {
+                let hash = hash_cache::hash_file(&path, &meta)?;
+                visit(Visit::File(&path, hash))?;
+                hash
+            }
+            FileKind::Symlink => {
+                let target = read_symlink(&path)?;
+                let hash = blake3::hash(&target);
+                visit(Visit::Bytes(&target, hash))?;
+                hash
+            }
+            FileKind::Dir => hash_dir(&path, visit)?,
+        };
+        tree.0.push(TreeEntry { name, kind, hash });
+    }
+    let obj = tree.to_string();
+    let hash = blake3::hash(obj.as_bytes());
+    visit(Visit::Bytes(obj.as_bytes(), hash))?;
+    Ok(hash)
+}
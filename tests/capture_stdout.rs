@@ -0,0 +1,87 @@
+//! `redux.captureStdout` with dofiles which depend on other targets
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// A fresh git repo containing the given dofiles, with stdout capture on
+fn repo(dofiles: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("redux-test-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for (name, txt) in dofiles {
+        std::fs::write(dir.join(name), txt).unwrap();
+    }
+    git(&dir, &["init", "--quiet"]);
+    git(&dir, &["config", "redux.captureStdout", "true"]);
+    git(&dir, &["add", "."]);
+    git(
+        &dir,
+        &[
+            "-c",
+            "user.name=test",
+            "-c",
+            "user.email=test@example.com",
+            "commit",
+            "--quiet",
+            "-m",
+            "init",
+        ],
+    );
+    dir
+}
+
+fn git(dir: &Path, args: &[&str]) {
+    let status = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .status()
+        .unwrap();
+    assert!(status.success(), "git {args:?} failed");
+}
+
+fn redux(dir: &Path, target: &str) {
+    let exe = Path::new(env!("CARGO_BIN_EXE_redux"));
+    let path = std::env::join_paths(
+        std::iter::once(exe.parent().unwrap().to_owned())
+            .chain(std::env::split_paths(&std::env::var_os("PATH").unwrap())),
+    )
+    .unwrap();
+    let status = Command::new(exe)
+        .arg(target)
+        .current_dir(dir)
+        .env("PATH", path)
+        .env_remove("REDUX_TRACEFILE")
+        .env_remove("REDUX_BUILD_ID")
+        .status()
+        .unwrap();
+    assert!(status.success(), "redux {target} failed");
+}
+
+#[test]
+fn captured_output_with_a_dependency() {
+    let dir = repo(&[
+        ("dep.txt.do", "echo hello\n"),
+        ("out.txt.do", "redux dep.txt\ncat dep.txt\n"),
+    ]);
+    redux(&dir, "out.txt");
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out.txt")).unwrap(),
+        "hello\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn written_output_with_a_dependency() {
+    let dir = repo(&[
+        ("dep.txt.do", "echo hello\n"),
+        ("out.txt.do", "redux dep.txt\ncp dep.txt \"$3\"\n"),
+    ]);
+    redux(&dir, "out.txt");
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out.txt")).unwrap(),
+        "hello\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}